@@ -1,30 +1,102 @@
-/*
-use std::fs::File;
-use std::io::Read;
+use std::env;
+use std::fs;
 use std::io::Write;
-use std::path::Path;
-*/
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
+// Builds every crate under user_space/ and packs the binaries into a
+// ustar archive in OUT_DIR, which the kernel embeds as its initrd,
+// along with the boot manifest. Set RL4_PREBUILT_USER_SPACE to pack
+// already built binaries instead
 fn main() {
-    /* // Uncomment this to generate the elf_data.rs file
-    let input_file = Path::new("/workspaces/RL4/user_space/hello_world/target/target/debug/hello_world");
-    let output_file = Path::new("/workspaces/RL4/elf_data.rs");
-
-    let mut file = File::open(input_file).expect("Failed to open input file");
-    let mut contents = Vec::new();
-    file.read_to_end(&mut contents).expect("Failed to read input file");
-
-    let mut output_file = File::create(output_file).expect("Failed to create output file");
-    output_file.write_all(b"pub const ELF_DATA: &[u8] = &[")
-        .expect("Failed to write output file");
-    for (i, byte) in contents.iter().enumerate() {
-        if i > 0 {
-            output_file.write_all(b", ").expect("Failed to write output file");
+    let root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let user_space = root.join("user_space");
+    let target_spec = root.join("target.json");
+    let skip_build = env::var_os("RL4_PREBUILT_USER_SPACE").is_some();
+    println!("cargo:rerun-if-env-changed=RL4_PREBUILT_USER_SPACE");
+    // Link the kernel into the top 2 GiB, clear of user space. See the
+    // address space layout in src/memory.rs
+    println!("cargo:rustc-link-arg-bins=--image-base=0xffffffff80000000");
+
+    let mut crates: Vec<PathBuf> = fs::read_dir(&user_space)
+        .expect("user_space directory missing")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.join("Cargo.toml").exists())
+        .collect();
+    crates.sort();
+
+    let mut archive = Vec::new();
+    for crate_dir in crates {
+        let name = crate_dir.file_name().unwrap().to_str().unwrap().to_string();
+        // Not the whole crate, whose target directory changes on every
+        // build
+        println!("cargo:rerun-if-changed={}", crate_dir.join("src").display());
+        println!("cargo:rerun-if-changed={}", crate_dir.join("Cargo.toml").display());
+        if !skip_build {
+            build_user_crate(&crate_dir, &target_spec);
+        }
+        let binary = crate_dir.join("target/target/debug").join(&name);
+        if skip_build {
+            println!("cargo:rerun-if-changed={}", binary.display());
         }
-        output_file.write_all(format!("0x{:02X}", byte).as_bytes())
-            .expect("Failed to write output file");
+        let data = fs::read(&binary)
+            .unwrap_or_else(|e| panic!("could not read {}: {}", binary.display(), e));
+        append_file(&mut archive, &name, &data);
+    }
+    let manifest_path = user_space.join("init.manifest");
+    println!("cargo:rerun-if-changed={}", manifest_path.display());
+    let manifest = fs::read(&manifest_path).expect("user_space/init.manifest missing");
+    append_file(&mut archive, "init.manifest", &manifest);
+
+    // Two zero blocks end the archive
+    archive.resize(archive.len() + 1024, 0);
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::File::create(out_dir.join("initrd.tar"))
+        .and_then(|mut file| file.write_all(&archive))
+        .expect("could not write initrd.tar");
+}
+
+fn build_user_crate(crate_dir: &Path, target_spec: &Path) {
+    // Don't leak the kernel build's settings into the nested build
+    let status = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+        .current_dir(crate_dir)
+        .env_remove("RUSTFLAGS")
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .env_remove("CARGO_TARGET_DIR")
+        .env_remove("CARGO_BUILD_TARGET")
+        .args(["build", "--target"])
+        .arg(target_spec)
+        .args(["-Z", "build-std=core,compiler_builtins",
+               "-Z", "build-std-features=compiler-builtins-mem"])
+        .status()
+        .expect("could not run cargo for user_space");
+    if !status.success() {
+        panic!("building {} failed", crate_dir.display());
     }
-    output_file.write_all(b"];").expect("Failed to write output file");
-    */
 }
 
+// Append a regular file to a ustar archive
+fn append_file(archive: &mut Vec<u8>, name: &str, data: &[u8]) {
+    assert!(name.len() < 100, "file name too long for ustar: {}", name);
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(b"0000755\0");    // mode
+    header[108..116].copy_from_slice(b"0000000\0");    // uid
+    header[116..124].copy_from_slice(b"0000000\0");    // gid
+    header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0"); // mtime
+    header[156] = b'0';                                 // regular file
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field set to spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    let padding = (512 - data.len() % 512) % 512;
+    archive.resize(archive.len() + padding, 0);
+}
\ No newline at end of file
@@ -0,0 +1,130 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use core::ptr;
+use crate::memory;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const SDT_HEADER_SIZE: u64 = 36;
+
+// MADT entry types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub address: u64,
+    pub gsi_base: u32,
+}
+
+// An ISA IRQ which is wired to a different global system interrupt
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16, // MPS INTI polarity and trigger mode
+}
+
+// Interrupt controller layout described by the MADT
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub processors: Vec<u8>, // Local APIC IDs of usable processors
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+fn read<T: Copy>(physaddr: u64) -> T {
+    unsafe { ptr::read_unaligned(memory::physical_to_virtual(physaddr).as_ptr::<T>()) }
+}
+
+fn checksum_ok(physaddr: u64, length: u64) -> bool {
+    (0..length).fold(0u8, |sum, i| sum.wrapping_add(read::<u8>(physaddr + i))) == 0
+}
+
+// Search the EBDA and the BIOS area for the Root System Description Pointer
+fn find_rsdp() -> Option<u64> {
+    let ebda = (read::<u16>(0x40e) as u64) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe0000, 0x100000)];
+    for (start, end) in areas {
+        if start == 0 {
+            continue;
+        }
+        for address in (start..end).step_by(16) {
+            if read::<[u8; 8]>(address) == *RSDP_SIGNATURE && checksum_ok(address, 20) {
+                return Some(address);
+            }
+        }
+    }
+    None
+}
+
+// Walk the RSDT (or XSDT on ACPI 2.0+) looking for a table
+fn find_table(signature: &[u8; 4]) -> Option<u64> {
+    let rsdp = find_rsdp()?;
+    let revision = read::<u8>(rsdp + 15);
+    let (root, entry_size) = if revision >= 2 {
+        (read::<u64>(rsdp + 24), 8)
+    } else {
+        (read::<u32>(rsdp + 16) as u64, 4)
+    };
+
+    let length = read::<u32>(root + 4) as u64;
+    let entries = (length - SDT_HEADER_SIZE) / entry_size;
+    for i in 0..entries {
+        let entry = root + SDT_HEADER_SIZE + i * entry_size;
+        let table = if entry_size == 8 { read::<u64>(entry) } else { read::<u32>(entry) as u64 };
+        if read::<[u8; 4]>(table) == *signature
+            && checksum_ok(table, read::<u32>(table + 4) as u64) {
+            return Some(table);
+        }
+    }
+    None
+}
+
+pub fn parse_madt() -> Option<Madt> {
+    let table = find_table(MADT_SIGNATURE)?;
+    let length = read::<u32>(table + 4) as u64;
+
+    let mut madt = Madt {
+        local_apic_address: read::<u32>(table + 36) as u64,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    // Entries follow the local APIC address and flags fields
+    let mut entry = table + 44;
+    while entry + 2 <= table + length {
+        let entry_type = read::<u8>(entry);
+        let entry_length = read::<u8>(entry + 1) as u64;
+        if entry_length < 2 {
+            break;
+        }
+        match entry_type {
+            MADT_LOCAL_APIC => {
+                // Enabled or online capable
+                if read::<u32>(entry + 4) & 0b11 != 0 {
+                    madt.processors.push(read::<u8>(entry + 3));
+                }
+            }
+            MADT_IO_APIC => madt.io_apics.push(IoApicInfo {
+                address: read::<u32>(entry + 4) as u64,
+                gsi_base: read::<u32>(entry + 8),
+            }),
+            MADT_INTERRUPT_OVERRIDE => madt.overrides.push(InterruptOverride {
+                irq: read::<u8>(entry + 3),
+                gsi: read::<u32>(entry + 4),
+                flags: read::<u16>(entry + 8),
+            }),
+            MADT_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                madt.local_apic_address = read::<u64>(entry + 4);
+            }
+            _ => {}
+        }
+        entry += entry_length;
+    }
+    Some(madt)
+}
\ No newline at end of file
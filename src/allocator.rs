@@ -1,7 +1,7 @@
 use x86_64::{structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB},VirtAddr};
 use linked_list_allocator::LockedHeap;
 
-pub const HEAP_START: usize = 0x8000000;
+pub const HEAP_START: usize = 0xffff_e000_0000_0000;
 pub const HEAP_SIZE: usize = 1000 * 4096; // 4 MiB
 
 #[global_allocator]
@@ -0,0 +1,277 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::RwLock;
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+use crate::acpi::{self, InterruptOverride};
+use crate::cpu;
+use crate::memory;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC register offsets
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TPR: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SVR: u64 = 0xf0;
+const LAPIC_ICR_LOW: u64 = 0x300;
+const LAPIC_ICR_HIGH: u64 = 0x310;
+const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_TIMER_INITIAL: u64 = 0x380;
+const LAPIC_TIMER_CURRENT: u64 = 0x390;
+const LAPIC_TIMER_DIVIDE: u64 = 0x3e0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// Interrupt command register fields
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_INIT: u32 = 0x4500; // INIT, level assert
+const ICR_STARTUP: u32 = 0x4600; // Start-up IPI, vector is the page number
+
+// IOAPIC registers, accessed through a select/window pair
+const IOAPIC_REGSEL: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// Length of the PIT measurement used to calibrate the LAPIC timer
+const CALIBRATION_MS: u32 = 10;
+
+struct IoApic {
+    base: u64,
+    gsi_base: u32,
+    entries: u32,
+}
+
+// Virtual address of the local APIC registers. Zero while the legacy
+// PIC is in use
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+// LAPIC timer count which gives one scheduler tick
+static TIMER_COUNT: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref IO_APICS: RwLock<Vec<IoApic>> = RwLock::new(Vec::new());
+    static ref OVERRIDES: RwLock<Vec<InterruptOverride>> = RwLock::new(Vec::new());
+    static ref PROCESSORS: RwLock<Vec<u8>> = RwLock::new(Vec::new());
+}
+
+pub fn is_enabled() -> bool {
+    LAPIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+fn cpu_has_apic() -> bool {
+    __cpuid(1).edx & (1 << 9) != 0
+}
+
+fn lapic_read(register: u64) -> u32 {
+    let address = LAPIC_BASE.load(Ordering::Relaxed) + register;
+    unsafe { (address as *const u32).read_volatile() }
+}
+
+fn lapic_write(register: u64, value: u32) {
+    let address = LAPIC_BASE.load(Ordering::Relaxed) + register;
+    unsafe { (address as *mut u32).write_volatile(value) }
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ((self.base + IOAPIC_REGSEL) as *mut u32).write_volatile(register);
+            ((self.base + IOAPIC_WINDOW) as *const u32).read_volatile()
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ((self.base + IOAPIC_REGSEL) as *mut u32).write_volatile(register);
+            ((self.base + IOAPIC_WINDOW) as *mut u32).write_volatile(value);
+        }
+    }
+
+    fn set_redirection(&self, index: u32, low: u32, high: u32) {
+        // Write the high half first so the entry is never unmasked
+        // with a stale destination
+        self.write(IOAPIC_REDIRECTION_TABLE + 2 * index + 1, high);
+        self.write(IOAPIC_REDIRECTION_TABLE + 2 * index, low);
+    }
+}
+
+// Set up the local APIC, its timer and any IOAPICs listed in the
+// ACPI MADT, and route each ISA IRQ in `legacy_irqs` to its vector.
+// Returns false if there is no APIC, in which case the caller should
+// keep using the 8259 PIC and the PIT
+pub fn init(timer_vector: u8, legacy_irqs: &[(u8, u8)]) -> bool {
+    if !cpu_has_apic() {
+        return false;
+    }
+    let madt = match acpi::parse_madt() {
+        Some(madt) => madt,
+        None => return false
+    };
+    let lapic = match memory::map_mmio(madt.local_apic_address, 4096) {
+        Ok(address) => address,
+        Err(_) => return false
+    };
+    LAPIC_BASE.store(lapic.as_u64(), Ordering::SeqCst);
+
+    for info in madt.io_apics.iter() {
+        if let Ok(base) = memory::map_mmio(info.address, 4096) {
+            let mut io_apic = IoApic { base: base.as_u64(), gsi_base: info.gsi_base, entries: 0 };
+            io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+            for index in 0..io_apic.entries {
+                io_apic.set_redirection(index, REDIRECTION_MASKED, 0);
+            }
+            IO_APICS.write().push(io_apic);
+        }
+    }
+    *OVERRIDES.write() = madt.overrides;
+    *PROCESSORS.write() = madt.processors;
+
+    TIMER_COUNT.store(calibrate_timer(), Ordering::SeqCst);
+    init_local(timer_vector);
+    for &(irq, vector) in legacy_irqs {
+        if !route_irq(irq, vector) {
+            println!("No IOAPIC input for IRQ {}", irq);
+        }
+    }
+    true
+}
+
+// Enable the local APIC of the calling CPU and start its timer
+pub fn init_local(timer_vector: u8) {
+    unsafe {
+        let mut base_msr = Msr::new(IA32_APIC_BASE_MSR);
+        let value = base_msr.read();
+        base_msr.write(value | APIC_BASE_ENABLE);
+    }
+    lapic_write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    lapic_write(LAPIC_TPR, 0);
+
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic_write(LAPIC_LVT_TIMER, LVT_TIMER_PERIODIC | timer_vector as u32);
+    lapic_write(LAPIC_TIMER_INITIAL, TIMER_COUNT.load(Ordering::SeqCst) as u32);
+}
+
+// Start PIT channel 2 counting down `count` ticks as a one-shot
+fn pit_oneshot_start(count: u32) {
+    let mut gate: Port<u8> = Port::new(0x61);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_2: Port<u8> = Port::new(0x42);
+    unsafe {
+        // Raise the channel 2 gate with the speaker disconnected
+        let value = gate.read();
+        gate.write((value & !0x03) | 0x01);
+
+        command.write(0xb0); // Channel 2, lobyte/hibyte, mode 0
+        channel_2.write((count & 0xff) as u8);
+        channel_2.write((count >> 8) as u8);
+    }
+}
+
+// Channel 2 output goes high when the count reaches zero
+fn pit_oneshot_wait() {
+    let mut gate: Port<u8> = Port::new(0x61);
+    unsafe { while gate.read() & 0x20 == 0 {} }
+}
+
+// Busy wait using the PIT, for up to about 50 ms at a time
+pub fn delay_us(microseconds: u32) {
+    let count = (cpu::PIT_FREQUENCY_HZ as u64 * microseconds as u64 / 1_000_000).clamp(1, 0xffff);
+    pit_oneshot_start(count as u32);
+    pit_oneshot_wait();
+}
+
+// Count how fast the LAPIC timer runs by letting the PIT channel 2
+// one-shot measure out CALIBRATION_MS milliseconds
+fn calibrate_timer() -> u64 {
+    lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+
+    pit_oneshot_start(cpu::PIT_FREQUENCY_HZ * CALIBRATION_MS / 1000);
+    lapic_write(LAPIC_TIMER_INITIAL, u32::MAX);
+    pit_oneshot_wait();
+    let elapsed = u32::MAX - lapic_read(LAPIC_TIMER_CURRENT);
+    lapic_write(LAPIC_TIMER_INITIAL, 0);
+
+    elapsed as u64 * 1000 / (CALIBRATION_MS as u64 * cpu::TIMER_FREQUENCY_HZ as u64)
+}
+
+pub fn end_of_interrupt() {
+    lapic_write(LAPIC_EOI, 0);
+}
+
+// Local APIC IDs of all usable processors, including this one
+pub fn processors() -> Vec<u8> {
+    PROCESSORS.read().clone()
+}
+
+pub fn local_apic_id() -> u8 {
+    (lapic_read(LAPIC_ID) >> 24) as u8
+}
+
+fn send_command(apic_id: u8, command: u32) {
+    lapic_write(LAPIC_ICR_HIGH, (apic_id as u32) << 24);
+    // Writing the low half sends the interrupt
+    lapic_write(LAPIC_ICR_LOW, command);
+    while lapic_read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+// Send a fixed interrupt to another CPU. Does nothing with the legacy
+// PIC, where there is only one CPU
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    if is_enabled() {
+        send_command(apic_id, vector as u32);
+    }
+}
+
+// Wake an application processor with the INIT-SIPI-SIPI sequence. It
+// starts in real mode at `start_address`, which must be page aligned
+// and below 1 MiB
+pub fn start_ap(apic_id: u8, start_address: u64) {
+    send_command(apic_id, ICR_INIT);
+    delay_us(10_000);
+    for _ in 0..2 {
+        send_command(apic_id, ICR_STARTUP | (start_address >> 12) as u32);
+        delay_us(200);
+    }
+}
+
+// Deliver an ISA IRQ to `vector` on this CPU, applying any source
+// override from the MADT. Returns false if no IOAPIC handles it
+fn route_irq(irq: u8, vector: u8) -> bool {
+    let (gsi, flags) = OVERRIDES.read().iter()
+        .find(|o| o.irq == irq)
+        .map_or((irq as u32, 0), |o| (o.gsi, o.flags));
+
+    let mut low = vector as u32;
+    if flags & 0b11 == 0b11 {
+        low |= REDIRECTION_ACTIVE_LOW;
+    }
+    if (flags >> 2) & 0b11 == 0b11 {
+        low |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    let high = (local_apic_id() as u32) << 24;
+
+    for io_apic in IO_APICS.read().iter() {
+        if gsi >= io_apic.gsi_base && gsi < io_apic.gsi_base + io_apic.entries {
+            io_apic.set_redirection(gsi - io_apic.gsi_base, low, high);
+            return true;
+        }
+    }
+    false
+}
\ No newline at end of file
@@ -1,6 +1,6 @@
 use core::arch::asm;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub struct RegisterState {
     pub r15: u64,
@@ -1,2 +1 @@
-pub mod arch;
-pub mod elf;
\ No newline at end of file
+pub mod arch;
\ No newline at end of file
@@ -1,27 +1,52 @@
 use x86_64::structures::idt::InterruptDescriptorTable;
 use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::PrivilegeLevel;
 use core::arch::asm;
+#[cfg(feature = "kpti")]
+use core::mem::size_of;
+#[cfg(feature = "kpti")]
+use x86_64::VirtAddr;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
+use crate::apic;
+use crate::faults::{self, FaultKind};
 use crate::gdt;
+use crate::pager;
+use crate::pcid;
+use crate::percpu;
+use crate::process;
 use crate::threads;
+use crate::usercopy;
 use crate::arch::arch::RegisterState;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+// The PIT counts down from the divisor at this rate
+pub const PIT_FREQUENCY_HZ: u32 = 1193182;
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+// One scheduler tick is 1 ms
+pub const TIMER_FREQUENCY_HZ: u32 = 1000;
+// ISA IRQ and data port of the PS/2 keyboard
+const KEYBOARD_IRQ: u8 = 1;
+const KEYBOARD_DATA: u16 = 0x60;
+
 pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
+    Keyboard = PIC_1_OFFSET + KEYBOARD_IRQ,
+    Reschedule = 0xf0, // IPI sent when a thread is queued on another CPU
+    TlbShootdown = 0xf1, // IPI sent when a loaded address space changes
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -30,117 +55,441 @@ impl InterruptIndex {
     }
 }
 
+// Page aligned, so that kpti user page tables can map it on its own
+#[repr(align(4096))]
+struct Idt(InterruptDescriptorTable);
+
 lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
+    static ref IDT: Idt = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
             idt.page_fault.set_handler_fn(page_fault_handler).set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
             idt.general_protection_fault.set_handler_fn(general_protection_fault_handler).set_stack_index(gdt::GENERAL_PROTECTION_FAULT_IST_INDEX);
+            // There is no ring 0 stack in the TSS, so every vector which
+            // can be raised from user mode needs an IST stack
+            idt.breakpoint.set_handler_fn(breakpoint_handler).set_stack_index(gdt::EXCEPTION_IST_INDEX)
+                .set_privilege_level(PrivilegeLevel::Ring3);
+            idt.divide_error.set_handler_fn(divide_error_handler).set_stack_index(gdt::EXCEPTION_IST_INDEX);
+            // User mode can set TF, so single steps trap here
+            idt.debug.set_handler_fn(debug_handler).set_stack_index(gdt::EXCEPTION_IST_INDEX);
+            idt.overflow.set_handler_fn(overflow_handler).set_stack_index(gdt::EXCEPTION_IST_INDEX);
+            idt.bound_range_exceeded.set_handler_fn(bound_range_handler).set_stack_index(gdt::EXCEPTION_IST_INDEX);
+            idt.invalid_opcode.set_handler_fn(invalid_opcode_handler).set_stack_index(gdt::EXCEPTION_IST_INDEX);
+            idt.device_not_available.set_handler_fn(device_not_available_handler).set_stack_index(gdt::EXCEPTION_IST_INDEX);
+            idt.invalid_tss.set_handler_fn(invalid_tss_handler).set_stack_index(gdt::EXCEPTION_IST_INDEX);
+            idt.segment_not_present.set_handler_fn(segment_not_present_handler).set_stack_index(gdt::EXCEPTION_IST_INDEX);
+            idt.stack_segment_fault.set_handler_fn(stack_segment_handler).set_stack_index(gdt::EXCEPTION_IST_INDEX);
+            idt.x87_floating_point.set_handler_fn(x87_floating_point_handler).set_stack_index(gdt::EXCEPTION_IST_INDEX);
+            idt.alignment_check.set_handler_fn(alignment_check_handler).set_stack_index(gdt::EXCEPTION_IST_INDEX);
+            idt.simd_floating_point.set_handler_fn(simd_floating_point_handler).set_stack_index(gdt::EXCEPTION_IST_INDEX);
             idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler).set_stack_index(gdt::TIMER_INTERRUPT_INDEX);
+            idt[InterruptIndex::Reschedule.as_usize()].set_handler_fn(reschedule_interrupt_handler).set_stack_index(gdt::TIMER_INTERRUPT_INDEX);
+            idt[InterruptIndex::TlbShootdown.as_usize()].set_handler_fn(tlb_shootdown_handler).set_stack_index(gdt::TIMER_INTERRUPT_INDEX);
+            idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler).set_stack_index(gdt::TIMER_INTERRUPT_INDEX);
         }
-        idt
+        Idt(idt)
     };
 }
 
 pub fn init_idt() {
-    IDT.load();
+    IDT.0.load();
+}
+
+#[cfg(feature = "kpti")]
+pub fn idt_range() -> (VirtAddr, u64) {
+    (VirtAddr::from_ptr(&IDT.0), size_of::<InterruptDescriptorTable>() as u64)
+}
+
+// Use the local APIC and IOAPICs when available, otherwise fall back
+// to the 8259 PIC driven by the PIT
+pub fn init_interrupt_controllers() {
+    // Remap the PIC even if it ends up disabled, so that spurious
+    // legacy interrupts don't land on exception vectors
+    unsafe { PICS.lock().initialize() };
+    let legacy_irqs = [(KEYBOARD_IRQ, InterruptIndex::Keyboard.as_u8())];
+    if apic::init(InterruptIndex::Timer.as_u8(), &legacy_irqs) {
+        unsafe { PICS.lock().disable() };
+        println!("Using local APIC timer");
+    } else {
+        init_timer();
+        println!("No APIC found, using 8259 PIC");
+    }
+}
+
+pub fn end_of_interrupt(interrupt: InterruptIndex) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(interrupt.as_u8());
+        }
+    }
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+// Program PIT channel 0 as a rate generator firing TIMER_FREQUENCY_HZ
+// times a second, instead of the ~18.2 Hz left by the BIOS
+fn init_timer() {
+    use x86_64::instructions::port::Port;
+    let divisor = PIT_FREQUENCY_HZ / TIMER_FREQUENCY_HZ;
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut channel_0: Port<u8> = Port::new(PIT_CHANNEL_0);
+    unsafe {
+        command.write(0x34); // Channel 0, lobyte/hibyte, mode 2
+        channel_0.write((divisor & 0xff) as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
 }
 
+// int3 in a thread with an exception handler goes to the handler.
+// Otherwise print some state and carry on
+extern "C" fn breakpoint_helper(context: &mut RegisterState) -> usize {
+    if context.cs & 3 == 3 && threads::current_has_exception_handler() {
+        return faults::handle_exception(context, FaultKind::Breakpoint, 0, 0);
+    }
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", context);
+    threads::dump_stats();
+    faults::dump_log();
+    0
+}
+
+// Spurious APIC interrupts must not be acknowledged
+#[cfg_attr(feature = "kpti", link_section = "entry_text")]
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
 extern "C" fn timer_interrupt_helper(context: &mut RegisterState) -> usize {
-    let next_stack = threads::schedule_next(context as *mut RegisterState as usize);
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    // Don't leave a thread switched away from last time waiting for
+    // this CPU to switch again
+    threads::finish_migration();
+    // Only switch threads when the timeslice has run out
+    let next_stack = if threads::timer_tick() {
+        threads::schedule_next(context as *mut RegisterState as usize)
+    } else {
+        0
+    };
+    end_of_interrupt(InterruptIndex::Timer);
     next_stack
 }
 
-#[naked]
-pub extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    unsafe {
-        asm!(
-            // Disable interrupts
-            "cli",
-            // Push registers
-            "push rax",
-            "push rbx",
-            "push rcx",
-            "push rdx",
-            "push rdi",
-            "push rsi",
-            "push rbp",
-            "push r8",
-            "push r9",
-            "push r10",
-            "push r11",
-            "push r12",
-            "push r13",
-            "push r14",
-            "push r15",
-        
-            // First argument in rdi with C calling convention
-            "mov rdi, rsp",
-            // Call the hander function
-            "call {handler}",
-            "cmp rax, 0",
-            "je 2f",        // if rax != 0 {
-            "mov rsp, rax", //   rsp = rax;
-            "2:",           // }
-        
-            // Pop scratch registers
-            "pop r15",
-            "pop r14",
-            "pop r13",
-            "pop r12",
-            "pop r11",
-            "pop r10",
-            "pop r9",
-            "pop r8",
-            "pop rbp",
-            "pop rsi",
-            "pop rdi",
-            "pop rdx",
-            "pop rcx",
-            "pop rbx",
-            "pop rax",
-            // Enable interrupts
-            "sti",
-            // Interrupt return
-            "iretq",
-            handler = sym timer_interrupt_helper,
-            options(noreturn)
-        );
+extern "C" fn reschedule_interrupt_helper(context: &mut RegisterState) -> usize {
+    let next_stack = if threads::reschedule_requested() {
+        threads::schedule_next(context as *mut RegisterState as usize)
+    } else {
+        0
+    };
+    apic::end_of_interrupt();
+    next_stack
+}
+
+extern "C" fn tlb_shootdown_helper(_context: &mut RegisterState) -> usize {
+    pcid::acknowledge();
+    apic::end_of_interrupt();
+    0
+}
+
+// Take the scancode so that the controller sends the next one. Nothing
+// reads the keyboard yet
+extern "C" fn keyboard_interrupt_helper(_context: &mut RegisterState) -> usize {
+    use x86_64::instructions::port::Port;
+    let mut data: Port<u8> = Port::new(KEYBOARD_DATA);
+    let _scancode = unsafe { data.read() };
+    end_of_interrupt(InterruptIndex::Keyboard);
+    0
+}
+
+// Entry code for interrupts which may switch threads. The registers
+// are saved as a RegisterState on the stack and `$helper` is called
+// with its address. If the helper returns a different RegisterState
+// address, that context is resumed instead
+macro_rules! interrupt_entry {
+    ($name:ident, $helper:ident) => {
+        #[naked]
+        #[cfg_attr(feature = "kpti", link_section = "entry_text")]
+        pub extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+            unsafe {
+                asm!(
+                    // Disable interrupts
+                    "cli",
+                    // Switch to the kernel GS, and with kpti the kernel
+                    // page table, if we came from user mode
+                    "test qword ptr [rsp + 8], 3",
+                    "jz 3f",
+                    "swapgs",
+                    ".if {kpti}",
+                    "push rax",
+                    "mov rax, gs:[{kernel_cr3}]",
+                    "mov cr3, rax",
+                    "pop rax",
+                    ".endif",
+                    "3:",
+                    // Push registers
+                    "push rax",
+                    "push rbx",
+                    "push rcx",
+                    "push rdx",
+                    "push rdi",
+                    "push rsi",
+                    "push rbp",
+                    "push r8",
+                    "push r9",
+                    "push r10",
+                    "push r11",
+                    "push r12",
+                    "push r13",
+                    "push r14",
+                    "push r15",
+
+                    // First argument in rdi with C calling convention
+                    "mov rdi, rsp",
+                    // Call the hander function
+                    "call {handler}",
+                    "cmp rax, 0",
+                    "je 2f",        // if rax != 0 {
+                    "mov rsp, rax", //   rsp = rax;
+                    "2:",           // }
+
+                    // Pop scratch registers
+                    "pop r15",
+                    "pop r14",
+                    "pop r13",
+                    "pop r12",
+                    "pop r11",
+                    "pop r10",
+                    "pop r9",
+                    "pop r8",
+                    "pop rbp",
+                    "pop rsi",
+                    "pop rdi",
+                    "pop rdx",
+                    "pop rcx",
+                    "pop rbx",
+                    // Back to the user GS and page table if we are
+                    // returning to user mode. rax is still free
+                    "test qword ptr [rsp + 16], 3",
+                    "jz 4f",
+                    ".if {kpti}",
+                    "mov rax, gs:[{user_cr3}]",
+                    "mov cr3, rax",
+                    "mov rax, gs:[{cr3_noflush}]",
+                    "or gs:[{user_cr3}], rax",
+                    ".endif",
+                    "swapgs",
+                    "4:",
+                    "pop rax",
+                    // Enable interrupts
+                    "sti",
+                    // Interrupt return
+                    "iretq",
+                    handler = sym $helper,
+                    kpti = const cfg!(feature = "kpti") as u8,
+                    kernel_cr3 = const percpu::KERNEL_CR3_OFFSET,
+                    user_cr3 = const percpu::USER_CR3_OFFSET,
+                    cr3_noflush = const percpu::CR3_NOFLUSH_OFFSET,
+                    options(noreturn)
+                );
+            }
+        }
+    };
+}
+
+// Same for exceptions which push an error code. The error code slot
+// is swapped with rax so that the stack again holds a RegisterState,
+// and the error code is passed as the second argument
+macro_rules! exception_entry {
+    ($name:ident, $helper:ident, $error_type:ty) => {
+        #[naked]
+        #[cfg_attr(feature = "kpti", link_section = "entry_text")]
+        pub extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame, _error_code: $error_type) {
+            unsafe {
+                asm!(
+                    // Switch to the kernel GS, and with kpti the kernel
+                    // page table, if we came from user mode
+                    "test qword ptr [rsp + 16], 3",
+                    "jz 3f",
+                    "swapgs",
+                    ".if {kpti}",
+                    "push rax",
+                    "mov rax, gs:[{kernel_cr3}]",
+                    "mov cr3, rax",
+                    "pop rax",
+                    ".endif",
+                    "3:",
+                    // Save rax in place of the error code
+                    "xchg rax, [rsp]",
+                    "push rbx",
+                    "push rcx",
+                    "push rdx",
+                    "push rdi",
+                    "push rsi",
+                    "push rbp",
+                    "push r8",
+                    "push r9",
+                    "push r10",
+                    "push r11",
+                    "push r12",
+                    "push r13",
+                    "push r14",
+                    "push r15",
+
+                    "mov rdi, rsp",
+                    "mov rsi, rax",
+                    "call {handler}",
+                    "cmp rax, 0",
+                    "je 2f",
+                    "mov rsp, rax",
+                    "2:",
+
+                    "pop r15",
+                    "pop r14",
+                    "pop r13",
+                    "pop r12",
+                    "pop r11",
+                    "pop r10",
+                    "pop r9",
+                    "pop r8",
+                    "pop rbp",
+                    "pop rsi",
+                    "pop rdi",
+                    "pop rdx",
+                    "pop rcx",
+                    "pop rbx",
+                    "test qword ptr [rsp + 16], 3",
+                    "jz 4f",
+                    ".if {kpti}",
+                    "mov rax, gs:[{user_cr3}]",
+                    "mov cr3, rax",
+                    "mov rax, gs:[{cr3_noflush}]",
+                    "or gs:[{user_cr3}], rax",
+                    ".endif",
+                    "swapgs",
+                    "4:",
+                    "pop rax",
+                    "iretq",
+                    handler = sym $helper,
+                    kpti = const cfg!(feature = "kpti") as u8,
+                    kernel_cr3 = const percpu::KERNEL_CR3_OFFSET,
+                    user_cr3 = const percpu::USER_CR3_OFFSET,
+                    cr3_noflush = const percpu::CR3_NOFLUSH_OFFSET,
+                    options(noreturn)
+                );
+            }
+        }
+    };
+}
+
+interrupt_entry!(timer_interrupt_handler, timer_interrupt_helper);
+interrupt_entry!(reschedule_interrupt_handler, reschedule_interrupt_helper);
+interrupt_entry!(tlb_shootdown_handler, tlb_shootdown_helper);
+interrupt_entry!(keyboard_interrupt_handler, keyboard_interrupt_helper);
+exception_entry!(page_fault_handler, page_fault_helper, PageFaultErrorCode);
+exception_entry!(general_protection_fault_handler, general_protection_fault_helper, u64);
+exception_entry!(invalid_tss_handler, invalid_tss_helper, u64);
+exception_entry!(segment_not_present_handler, segment_not_present_helper, u64);
+exception_entry!(stack_segment_handler, stack_segment_helper, u64);
+exception_entry!(alignment_check_handler, alignment_check_helper, u64);
+interrupt_entry!(breakpoint_handler, breakpoint_helper);
+interrupt_entry!(divide_error_handler, divide_error_helper);
+interrupt_entry!(debug_handler, debug_helper);
+interrupt_entry!(overflow_handler, overflow_helper);
+interrupt_entry!(bound_range_handler, bound_range_helper);
+interrupt_entry!(invalid_opcode_handler, invalid_opcode_helper);
+interrupt_entry!(device_not_available_handler, device_not_available_helper);
+interrupt_entry!(x87_floating_point_handler, x87_floating_point_helper);
+interrupt_entry!(simd_floating_point_handler, simd_floating_point_helper);
+
+// A fault in user mode goes to the thread's exception handler or
+// kills the thread. In the kernel it's a bug, unless it happened
+// while copying to or from user memory
+fn handle_exception(context: &mut RegisterState, kind: FaultKind, error_code: u64) -> usize {
+    if context.cs & 3 == 3 {
+        return faults::handle_exception(context, kind, error_code, 0);
+    }
+    if let Some(resume) = usercopy::fixup(context.rip) {
+        context.rip = resume;
+        return 0;
     }
+    panic!("EXCEPTION: {:?} (error code {:#x})\n{:#?}", kind, error_code, context);
+}
+
+// Helpers for exceptions without an error code
+macro_rules! exception_helper {
+    ($name:ident, $kind:expr) => {
+        extern "C" fn $name(context: &mut RegisterState) -> usize {
+            handle_exception(context, $kind, 0)
+        }
+    };
+}
+
+// Helpers for exceptions which push an error code
+macro_rules! exception_helper_with_error {
+    ($name:ident, $kind:expr) => {
+        extern "C" fn $name(context: &mut RegisterState, error_code: u64) -> usize {
+            handle_exception(context, $kind, error_code)
+        }
+    };
 }
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+exception_helper!(divide_error_helper, FaultKind::DivideError);
+exception_helper!(debug_helper, FaultKind::Debug);
+exception_helper!(overflow_helper, FaultKind::Overflow);
+exception_helper!(bound_range_helper, FaultKind::BoundRange);
+exception_helper!(invalid_opcode_helper, FaultKind::InvalidOpcode);
+exception_helper!(device_not_available_helper, FaultKind::DeviceNotAvailable);
+exception_helper!(x87_floating_point_helper, FaultKind::X87FloatingPoint);
+exception_helper!(simd_floating_point_helper, FaultKind::SimdFloatingPoint);
+exception_helper_with_error!(general_protection_fault_helper, FaultKind::GeneralProtection);
+exception_helper_with_error!(invalid_tss_helper, FaultKind::InvalidTss);
+exception_helper_with_error!(segment_not_present_helper, FaultKind::SegmentNotPresent);
+exception_helper_with_error!(stack_segment_helper, FaultKind::StackSegment);
+exception_helper_with_error!(alignment_check_helper, FaultKind::AlignmentCheck);
+
+// Untouched pages are filled in and copy-on-write pages copied by the
+// kernel. Other faults in user mode are sent to the thread's pager, if
+// it has one, and otherwise handled like other exceptions. In the
+// kernel only user copies may fault
+extern "C" fn page_fault_helper(context: &mut RegisterState, error_code: u64) -> usize {
     use x86_64::registers::control::Cr2;
+    let address = Cr2::read();
+    if context.cs & 3 == 3 {
+        let flags = PageFaultErrorCode::from_bits_truncate(error_code);
+        let write = flags.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+        // Retrying a fetch from a present page would fault again
+        let resolvable = !flags.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+            || (write && !flags.contains(PageFaultErrorCode::INSTRUCTION_FETCH));
+        if resolvable && process::handle_fault(address.as_u64(), write) {
+            return 0;
+        }
+        if let Some(next_stack) = pager::handle_fault(context, address.as_u64(), error_code) {
+            return next_stack;
+        }
+        return faults::handle_exception(context, FaultKind::PageFault, error_code, address.as_u64());
+    }
+    if let Some(resume) = usercopy::fixup(context.rip) {
+        context.rip = resume;
+        return 0;
+    }
     println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    println!("Accessed Address: {:?}", address);
+    println!("Error Code: {:?}", PageFaultErrorCode::from_bits_truncate(error_code));
+    println!("{:#?}", context);
     hlt_loop();
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) {
-    panic!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}", stack_frame);
-}
-
 pub fn hlt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
     }
 }
 
+// Keeps running after loading the user page table, so with kpti it is
+// part of the entry code
+#[inline(never)]
+#[cfg_attr(feature = "kpti", link_section = "entry_text")]
 pub fn launch_thread(context_addr: usize) -> ! {
     unsafe {
         asm!("mov rsp, rdi", // Set the stack to the RegisterState address
@@ -162,11 +511,26 @@ pub fn launch_thread(context_addr: usize) -> ! {
              "pop rdx",
              "pop rcx",
              "pop rbx",
+
+             // User GS and page table if the thread runs in user mode
+             "test qword ptr [rsp + 16], 3",
+             "jz 4f",
+             ".if {kpti}",
+             "mov rax, gs:[{user_cr3}]",
+             "mov cr3, rax",
+             "mov rax, gs:[{cr3_noflush}]",
+             "or gs:[{user_cr3}], rax",
+             ".endif",
+             "swapgs",
+             "4:",
              "pop rax",
 
              "sti", // Enable interrupts
              "iretq",// Interrupt return
              in("rdi") context_addr,
+             kpti = const cfg!(feature = "kpti") as u8,
+             user_cr3 = const percpu::USER_CR3_OFFSET,
+             cr3_noflush = const percpu::CR3_NOFLUSH_OFFSET,
              options(noreturn)
         );
     }
@@ -0,0 +1,192 @@
+extern crate alloc;
+use alloc::{boxed::Box, collections::{vec_deque::VecDeque, BTreeMap}, sync::Arc};
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::RwLock;
+use x86_64::instructions::interrupts;
+use crate::arch::arch::RegisterState;
+use crate::ipc::{Message, Rendezvous};
+use crate::pcid;
+use crate::threads::{self, Thread};
+
+// Number of fault records kept, oldest are dropped first
+const FAULT_LOG_SIZE: usize = 32;
+
+// CPU exception taken by a user thread. The values are the exception
+// vectors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum FaultKind {
+    DivideError = 0,
+    Debug = 1,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRange = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    StackSegment = 12,
+    GeneralProtection = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    SimdFloatingPoint = 19,
+}
+
+// Record layout copied out to user space by the fault_log syscall
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct FaultRecord {
+    pub thread: u64,
+    pub kind: FaultKind,
+    pub error_code: u64,
+    pub rip: u64,
+    pub address: u64, // CR2 for page faults, otherwise 0
+    pub tick: u64,
+}
+
+// Who a suspended thread is waiting for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Suspension {
+    Pager,
+    ExceptionHandler,
+}
+
+// What to do with a suspended thread once its fault is dealt with
+pub enum Resolution {
+    Resume,
+    Terminate,
+}
+
+struct Suspended {
+    reason: Suspension,
+    record: FaultRecord,
+    thread: Box<Thread>,
+}
+
+lazy_static! {
+    static ref FAULT_LOG: RwLock<VecDeque<FaultRecord>> = RwLock::new(VecDeque::new());
+    // Threads waiting for a reply from their pager or exception
+    // handler, by thread id. Held while a pager maps a page, so during
+    // shootdowns
+    static ref SUSPENDED_THREADS: spin::rwlock::RwLock<BTreeMap<u64, Suspended>, pcid::Acknowledge> =
+        spin::rwlock::RwLock::new(BTreeMap::new());
+}
+
+fn new_record(thread: &Thread, context: &RegisterState, kind: FaultKind, error_code: u64, address: u64) -> FaultRecord {
+    FaultRecord {
+        thread: thread.id(),
+        kind,
+        error_code,
+        rip: context.rip,
+        address,
+        tick: threads::ticks()
+    }
+}
+
+fn kill(thread: Box<Thread>, record: FaultRecord) {
+    println!("Thread {} killed by {:?} at {:#x} (error code {:#x}, address {:#x})",
+             record.thread, record.kind, record.rip, record.error_code, record.address);
+    {
+        let mut log = FAULT_LOG.write();
+        if log.len() == FAULT_LOG_SIZE {
+            log.pop_front();
+        }
+        log.push_back(record);
+    }
+    // Frees the kernel stack. We are on an exception stack or the
+    // stack of another thread so that's fine
+    drop(thread);
+}
+
+// Called for exceptions in user mode. The exception is sent to the
+// thread's exception handler if it has one, otherwise the thread is
+// killed. Returns the context of the next thread to run
+pub fn handle_exception(context: &RegisterState, kind: FaultKind, error_code: u64, address: u64) -> usize {
+    if let Some(thread) = threads::take_current_thread() {
+        let record = new_record(&thread, context, kind, error_code, address);
+        match thread.exception_handler() {
+            Some(handler) => {
+                let message = Message::Exception {
+                    thread: thread.id(),
+                    vector: kind as u64,
+                    error_code,
+                    address,
+                    registers: *context
+                };
+                suspend(thread, context, Suspension::ExceptionHandler, record, &handler, message);
+            }
+            None => kill(thread, record)
+        }
+    }
+    // Every CPU has an idle thread, so there is always one to run
+    threads::schedule_next(0)
+}
+
+// Park a thread which faulted in user mode and send `message` to
+// `endpoint`. If the endpoint is busy the message waits in its queue
+pub fn suspend(mut thread: Box<Thread>, context: &RegisterState, reason: Suspension, record: FaultRecord,
+               endpoint: &Arc<RwLock<Rendezvous>>, message: Message) {
+    let mut rendezvous = endpoint.write();
+    thread.save_context(context);
+    thread.block();
+    SUSPENDED_THREADS.write().insert(thread.id(), Suspended { reason, record, thread });
+
+    let (receiver, _) = rendezvous.send(None, message);
+    drop(rendezvous);
+    if let Some(receiver) = receiver {
+        threads::schedule_thread(receiver);
+    }
+}
+
+// Run `f` on a thread suspended for `reason`, then resume or kill it
+// as `f` decides. Returns `missing` if there is no such thread, and
+// leaves the thread suspended if `f` fails
+pub fn resolve<E>(thread_id: u64, reason: Suspension, missing: E,
+                  f: impl FnOnce(&mut Thread) -> Result<Resolution, E>) -> Result<(), E> {
+    let mut suspended = SUSPENDED_THREADS.write();
+    let resolution = match suspended.get_mut(&thread_id) {
+        Some(entry) if entry.reason == reason => f(&mut entry.thread)?,
+        _ => return Err(missing)
+    };
+    if let Some(entry) = suspended.remove(&thread_id) {
+        drop(suspended);
+        match resolution {
+            Resolution::Resume => threads::schedule_thread(entry.thread),
+            Resolution::Terminate => kill(entry.thread, entry.record),
+        }
+    }
+    Ok(())
+}
+
+// Run `f` on a thread suspended for `reason` without resuming it
+pub fn inspect<R>(thread_id: u64, reason: Suspension, f: impl FnOnce(&Thread) -> R) -> Option<R> {
+    SUSPENDED_THREADS.read().get(&thread_id)
+        .filter(|entry| entry.reason == reason)
+        .map(|entry| f(&entry.thread))
+}
+
+// Up to `max_records` of the most recent fault records, oldest first
+pub fn fault_log(max_records: usize) -> Vec<FaultRecord> {
+    interrupts::without_interrupts(|| {
+        let log = FAULT_LOG.read();
+        let skip = log.len().saturating_sub(max_records);
+        log.iter().skip(skip).copied().collect()
+    })
+}
+
+pub fn dump_log() {
+    let records: Vec<FaultRecord> = interrupts::without_interrupts(|| {
+        FAULT_LOG.read().iter().copied().collect()
+    });
+    if records.is_empty() {
+        return;
+    }
+    println!("Fault log");
+    println!(" thread     tick  fault               rip              address");
+    for r in records {
+        println!("{:>7} {:>8}  {:<18} {:#016x} {:#016x}",
+                 r.thread, r.tick, alloc::format!("{:?}", r.kind), r.rip, r.address);
+    }
+}
\ No newline at end of file
@@ -1,16 +1,23 @@
+extern crate alloc;
+use alloc::alloc::{alloc_zeroed, Layout};
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
-use lazy_static::lazy_static;
-use spin::Mutex;
+#[cfg(feature = "kpti")]
+use crate::memory;
+use crate::percpu;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 pub const PAGE_FAULT_IST_INDEX: u16 = 0;
 pub const GENERAL_PROTECTION_FAULT_IST_INDEX: u16 = 0;
+pub const EXCEPTION_IST_INDEX: u16 = 0;
 pub const TIMER_INTERRUPT_INDEX: u16 = 1;
 pub const SYSCALL_TEMP_INDEX: u16 = 2;
 
-struct Selectors {
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
     code_selector: SegmentSelector,
     data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
@@ -18,67 +25,71 @@ struct Selectors {
     user_code_selector: SegmentSelector,
 }
 
-lazy_static! {
-    static ref TSS: Mutex<TaskStateSegment> = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-
-        tss.interrupt_stack_table[TIMER_INTERRUPT_INDEX as usize] = tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize];
-    
-        Mutex::new(tss)
+// Each CPU gets its own TSS, with its own double fault stack
+pub fn new_tss() -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        // Never freed: the stack is used until the CPU is reset. Page
+        // aligned, so that kpti user page tables can map it on its own
+        let layout = Layout::from_size_align(DOUBLE_FAULT_STACK_SIZE, 4096).unwrap();
+        let stack = unsafe { alloc_zeroed(layout) };
+        assert!(!stack.is_null(), "no memory for the exception stack");
+        let stack_start = VirtAddr::from_ptr(stack);
+        #[cfg(feature = "kpti")]
+        memory::map_entry_range(stack_start, DOUBLE_FAULT_STACK_SIZE as u64);
+        let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE;
+        stack_end
     };
-}
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let tss = unsafe {tss_reference()};
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
-        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
-        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
-        (gdt, Selectors { code_selector, data_selector, tss_selector, user_code_selector, user_data_selector})
-    };
+    tss.interrupt_stack_table[TIMER_INTERRUPT_INDEX as usize] = tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize];
+    tss
 }
 
-unsafe fn tss_reference() -> &'static TaskStateSegment {
-    let tss_ptr = &*TSS.lock() as *const TaskStateSegment;
-    & *tss_ptr
+// The descriptor layout is the same on every CPU, so the selectors are too
+pub fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    (gdt, Selectors { code_selector, data_selector, tss_selector, user_code_selector, user_data_selector})
 }
 
 pub fn set_interrupt_stack_table(index: usize, stack_end: VirtAddr) {
-    TSS.lock().interrupt_stack_table[index] = stack_end;
+    unsafe { (*percpu::current().tss_ptr()).interrupt_stack_table[index] = stack_end };
 }
 
 pub fn get_kernel_segments() -> (SegmentSelector, SegmentSelector) {
-    (GDT.1.code_selector, GDT.1.data_selector)
+    let selectors = percpu::current().selectors();
+    (selectors.code_selector, selectors.data_selector)
 }
 
 pub fn get_user_segments() -> (SegmentSelector, SegmentSelector) {
-    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+    let selectors = percpu::current().selectors();
+    (selectors.user_code_selector, selectors.user_data_selector)
 }
 
+// Address the syscall entry code finds through GS. The TSS is at the
+// start of the per-CPU block, so this is also the TSS address
 pub fn tss_address() -> u64 {
-    let tss_ptr = &*TSS.lock() as *const TaskStateSegment;
-    tss_ptr as u64
+    percpu::current() as *const percpu::PerCpu as u64
 }
 
-pub fn init() {
+// Load the GDT and TSS of the calling CPU
+pub fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
     use x86_64::instructions::tables::load_tss;
-    use x86_64::instructions::segmentation::{CS, DS, Segment};
-    
-    GDT.0.load();
+    use x86_64::instructions::segmentation::{CS, DS, SS, Segment};
+
+    gdt.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        DS::set_reg(GDT.1.data_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(selectors.code_selector);
+        DS::set_reg(selectors.data_selector);
+        SS::set_reg(selectors.data_selector);
+        load_tss(selectors.tss_selector);
     }
-}
\ No newline at end of file
+}
+
+pub fn init() {
+    percpu::init(0);
+}
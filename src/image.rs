@@ -0,0 +1,164 @@
+extern crate alloc;
+use alloc::{collections::BTreeMap, vec::Vec};
+use lazy_static::lazy_static;
+use spin::RwLock;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+use crate::memory::{self, COPY_ON_WRITE};
+
+const PAGE_SIZE: u64 = 4096;
+
+// The file a program is loaded from
+pub enum ImageData {
+    // Pages of initrd programs are shared by every process running
+    // the same file, see SHARED_PAGES
+    Initrd(&'static [u8]),
+    Copied(Vec<u8>), // Passed in by the spawning process
+}
+
+impl ImageData {
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            ImageData::Initrd(bytes) => bytes,
+            ImageData::Copied(bytes) => bytes
+        }
+    }
+}
+
+// A PT_LOAD segment at its final address
+pub struct Segment {
+    pub address: u64,
+    pub memory_size: u64,
+    pub file_offset: u64,
+    pub file_size: u64,
+    pub flags: PageTableFlags,
+}
+
+// A program in user space. Its pages are filled from the file and
+// relocated when first touched
+pub struct Image {
+    data: ImageData,
+    segments: Vec<Segment>, // Sorted by address, not overlapping
+    // Address and value of each relocated word. Each lies in a segment
+    relocations: Vec<(u64, u64)>,
+}
+
+lazy_static! {
+    // Filled pages of initrd programs, by file and page address. Each
+    // holds a reference to its frame, so the next process running the
+    // file finds the page ready. Programs are always loaded at the same
+    // address, so the relocated contents are the same too
+    static ref SHARED_PAGES: RwLock<BTreeMap<(u64, u64), PhysFrame>> = RwLock::new(BTreeMap::new());
+}
+
+impl Image {
+    pub fn new(data: ImageData, segments: Vec<Segment>, mut relocations: Vec<(u64, u64)>) -> Image {
+        relocations.sort_by_key(|&(address, _)| address);
+        Image { data, segments, relocations }
+    }
+
+    // Page aligned ranges covered by segments, as start address and
+    // page count. Segments sharing a page are merged
+    pub fn ranges(&self) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for segment in self.segments.iter().filter(|segment| segment.memory_size != 0) {
+            let start = segment.address & !(PAGE_SIZE - 1);
+            let end = (segment.address + segment.memory_size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            match ranges.last_mut() {
+                Some((last_start, last_pages)) if *last_start + *last_pages * PAGE_SIZE >= start => {
+                    *last_pages = (end - *last_start) / PAGE_SIZE;
+                }
+                _ => ranges.push((start, (end - start) / PAGE_SIZE))
+            }
+        }
+        ranges
+    }
+
+    // A page shared by two segments gets the more permissive flags of
+    // the two. None if no segment touches the page
+    fn page_flags(&self, address: u64) -> Option<PageTableFlags> {
+        self.segments.iter()
+            .filter(|segment| segment.memory_size != 0 && segment.address < address + PAGE_SIZE
+                    && segment.address + segment.memory_size > address)
+            .map(|segment| segment.flags)
+            .reduce(|a, b| ((a | b) & PageTableFlags::WRITABLE) | (a & b & PageTableFlags::NO_EXECUTE))
+    }
+
+    // A zeroed frame with the file contents and relocations of the page
+    // at `address`
+    fn fill(&self, address: u64) -> Option<PhysFrame> {
+        let frame = memory::allocate_user_frame()?;
+        let page = unsafe {
+            &mut *memory::physical_to_virtual(frame.start_address().as_u64()).as_mut_ptr::<[u8; PAGE_SIZE as usize]>()
+        };
+        let bytes = self.data.bytes();
+        for segment in self.segments.iter() {
+            let start = u64::max(segment.address, address);
+            let end = u64::min(segment.address + segment.file_size, address + PAGE_SIZE);
+            if start < end {
+                let offset = (segment.file_offset + start - segment.address) as usize;
+                page[(start - address) as usize..(end - address) as usize]
+                    .copy_from_slice(&bytes[offset..][..(end - start) as usize]);
+            }
+        }
+        // A word may straddle the start of the page
+        let first = self.relocations.partition_point(|&(target, _)| target + 8 <= address);
+        for &(target, value) in self.relocations[first..].iter().take_while(|&&(target, _)| target < address + PAGE_SIZE) {
+            for (i, byte) in value.to_le_bytes().iter().enumerate() {
+                let byte_address = target + i as u64;
+                if byte_address >= address && byte_address < address + PAGE_SIZE {
+                    page[(byte_address - address) as usize] = *byte;
+                }
+            }
+        }
+        Some(frame)
+    }
+
+    // Map the page containing `address`. Writable pages of initrd
+    // programs are mapped copy-on-write. Returns false if no segment
+    // touches the page, or there is no memory
+    pub fn map_page(&self, page_table_physaddr: u64, address: u64) -> bool {
+        let address = address & !(PAGE_SIZE - 1);
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(address));
+        let flags = match self.page_flags(address) {
+            Some(flags) => flags,
+            None => return false
+        };
+        match self.data {
+            ImageData::Initrd(bytes) => {
+                let key = (bytes.as_ptr() as u64, address);
+                let frame = interrupts::without_interrupts(|| {
+                    let mut shared = SHARED_PAGES.write();
+                    match shared.get(&key) {
+                        Some(&frame) => Some(frame),
+                        None => {
+                            let frame = self.fill(address)?;
+                            shared.insert(key, frame);
+                            Some(frame)
+                        }
+                    }
+                });
+                let frame = match frame {
+                    Some(frame) => frame,
+                    None => return false
+                };
+                let flags = if flags.contains(PageTableFlags::WRITABLE) {
+                    (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+                } else {
+                    flags
+                };
+                memory::map_user_page(page_table_physaddr, page, Some(frame), flags).is_ok()
+            }
+            ImageData::Copied(_) => {
+                let frame = match self.fill(address) {
+                    Some(frame) => frame,
+                    None => return false
+                };
+                let mapped = memory::map_user_page(page_table_physaddr, page, Some(frame), flags).is_ok();
+                memory::release_frame(frame);
+                mapped
+            }
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,64 @@
+// Initial ramdisk: a ustar archive of the user programs, packed by
+// build.rs and embedded in the kernel image
+static INITRD: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.tar"));
+
+const BLOCK_SIZE: usize = 512;
+
+pub struct File {
+    pub name: &'static str,
+    pub data: &'static [u8],
+}
+
+// Parse a NUL or space terminated octal field
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let mut value = 0usize;
+    for &byte in field.iter().take_while(|&&b| b != 0 && b != b' ') {
+        if !(b'0'..=b'7').contains(&byte) {
+            return None;
+        }
+        value = value.checked_mul(8)?.checked_add((byte - b'0') as usize)?;
+    }
+    Some(value)
+}
+
+fn parse_name(field: &'static [u8]) -> Option<&'static str> {
+    let length = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..length]).ok()
+}
+
+pub struct Files {
+    offset: usize,
+}
+
+impl Iterator for Files {
+    type Item = File;
+
+    fn next(&mut self) -> Option<File> {
+        loop {
+            let header = INITRD.get(self.offset..self.offset + BLOCK_SIZE)?;
+            // An all zero block marks the end of the archive
+            if header.iter().all(|&b| b == 0) {
+                return None;
+            }
+            let size = parse_octal(&header[124..136])?;
+            let data_start = self.offset + BLOCK_SIZE;
+            let data = INITRD.get(data_start..data_start + size)?;
+            self.offset = data_start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+
+            // Only regular files. Old archives use NUL as the type
+            let typeflag = header[156];
+            if typeflag != b'0' && typeflag != 0 {
+                continue;
+            }
+            return Some(File { name: parse_name(&header[0..100])?, data });
+        }
+    }
+}
+
+pub fn files() -> Files {
+    Files { offset: 0 }
+}
+
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    files().find(|file| file.name == name).map(|file| file.data)
+}
\ No newline at end of file
@@ -1,27 +1,40 @@
-use alloc::boxed::Box;
+use alloc::{boxed::Box, collections::vec_deque::VecDeque};
+use crate::arch::arch::RegisterState;
 use crate::threads::Thread;
 use core::mem;
 
 pub enum Message {
     Short(u64),
+    // Sent by the kernel to a pager when a thread faults
+    PageFault { thread: u64, address: u64, error_code: u64, rip: u64 },
+    // Sent to an exception handler, with the registers of the thread
+    // at the exception. They are copied to the buffer the handler
+    // passed to ipc_read
+    Exception { thread: u64, vector: u64, error_code: u64, address: u64, registers: RegisterState },
 }
 
 pub enum Rendezvous {
     Empty,
-    Sending(Option<Box<Thread>>, Message),
+    // Messages from the kernel, which has no thread to block, queue
+    // up behind the one being sent
+    Sending(Option<Box<Thread>>, Message, VecDeque<Message>),
     Receiving(Box<Thread>)
 }
 
 impl Rendezvous {
-    pub fn send(&mut self, thread: Option<Box<Thread>>, message: Message) -> (Option<Box<Thread>>, Option<Box<Thread>>) {
-        match &*self {
+    pub fn send(&mut self, mut thread: Option<Box<Thread>>, message: Message) -> (Option<Box<Thread>>, Option<Box<Thread>>) {
+        match self {
             Rendezvous::Empty => {
-                *self = Rendezvous::Sending(thread, message);
+                if let Some(t) = &mut thread {
+                    t.block();
+                }
+                *self = Rendezvous::Sending(thread, message, VecDeque::new());
                 (None, None)
             }
-            Rendezvous::Sending(_, _) => {
-                if let Some(t) = &thread {
-                    t.return_error(1);
+            Rendezvous::Sending(_, _, queued) => {
+                match &thread {
+                    Some(t) => t.return_error(1),
+                    None => queued.push_back(message)
                 }
                 (thread, None)
             }
@@ -38,14 +51,18 @@ impl Rendezvous {
         }
     }
 
-    pub fn receive(&mut self, thread: Box<Thread>) -> (Option<Box<Thread>>, Option<Box<Thread>>) {
+    pub fn receive(&mut self, mut thread: Box<Thread>) -> (Option<Box<Thread>>, Option<Box<Thread>>) {
         match &*self {
             Rendezvous::Empty => {
+                thread.block();
                 *self = Rendezvous::Receiving(thread);
                 (None, None)
             }
-            Rendezvous::Sending(_, _) => {
-                if let Rendezvous::Sending(snd_thread, message) = mem::replace(self, Rendezvous::Empty) {
+            Rendezvous::Sending(_, _, _) => {
+                if let Rendezvous::Sending(snd_thread, message, mut queued) = mem::replace(self, Rendezvous::Empty) {
+                    if let Some(next) = queued.pop_front() {
+                        *self = Rendezvous::Sending(None, next, queued);
+                    }
                     thread.return_message(message);
                     if let Some(ref t) = snd_thread {
                         t.return_error(0);
@@ -0,0 +1,71 @@
+use core::mem::size_of;
+use core::sync::atomic::Ordering;
+use x86_64::VirtAddr;
+use crate::cpu;
+use crate::memory;
+use crate::pcid::{self, NOFLUSH, PCID_COUNT};
+use crate::percpu::{self, PerCpu};
+
+// Kernel page table isolation. Each process has a second page table
+// which user mode runs on. It maps user space, but of the kernel only
+// the entry code, the IDT, the per-CPU blocks holding the GDT and TSS,
+// and the stacks the TSS points to. The entry code switches to the
+// full table as soon as it comes from user mode, and back just before
+// returning
+
+// The user mode table of a process is tagged with the process's PCID
+// plus this, so switching between the two tables keeps both sets of
+// TLB entries
+const USER_PCID: u64 = PCID_COUNT as u64;
+
+// Start and end of the entry code, placed by the linker
+extern "C" {
+    static __start_entry_text: u8;
+    static __stop_entry_text: u8;
+}
+
+// Map what is shared by all CPUs. Called on the boot CPU once the IDT
+// is set up
+pub fn init() {
+    let (start, end) = unsafe {
+        (&__start_entry_text as *const u8 as u64, &__stop_entry_text as *const u8 as u64)
+    };
+    memory::map_entry_range(VirtAddr::new(start), end - start);
+    let (idt, idt_length) = cpu::idt_range();
+    memory::map_entry_range(idt, idt_length);
+    init_cpu();
+}
+
+// Map the calling CPU's block. The exception stack is mapped when the
+// TSS is made. Called on every CPU after pcid::init_cpu
+pub fn init_cpu() {
+    let cpu = percpu::current();
+    memory::map_entry_range(VirtAddr::from_ptr(cpu), size_of::<PerCpu>() as u64);
+    if pcid::enabled() {
+        cpu.cr3_noflush.store(NOFLUSH, Ordering::Relaxed);
+    }
+    let kernel = memory::kernel_page_table_physaddr();
+    set_page_tables(cpu, kernel, kernel, 0);
+}
+
+// Set the tables the entry code loads for the thread about to run. The
+// caller has just loaded `kernel` with pcid::load
+pub fn set_page_tables(cpu: &PerCpu, kernel: u64, user: u64, pcid: u16) {
+    let noflush = cpu.cr3_noflush.load(Ordering::Relaxed);
+    let (kernel, user) = if noflush != 0 {
+        (kernel | u64::from(pcid) | noflush, user | u64::from(pcid) | USER_PCID)
+    } else {
+        (kernel, user)
+    };
+    cpu.kernel_cr3.store(kernel, Ordering::Relaxed);
+    // A different table is flushed when first loaded
+    if cpu.user_cr3.load(Ordering::Relaxed) & !NOFLUSH != user {
+        cpu.user_cr3.store(user, Ordering::Relaxed);
+    }
+}
+
+// invlpg only reaches the current PCID, so after a user mapping changes
+// the user PCID is flushed on the next return to user mode
+pub fn invalidate_user_tlb() {
+    percpu::current().user_cr3.fetch_and(!NOFLUSH, Ordering::Relaxed);
+}
\ No newline at end of file
@@ -6,10 +6,7 @@
 
 use core::panic::PanicInfo;
 use bootloader::{BootInfo};
-use spin::RwLock;
-use lazy_static::lazy_static;
 extern crate alloc;
-use alloc::vec::Vec;
 
 #[macro_use]
 mod vga;
@@ -22,24 +19,42 @@ mod arch;
 mod gdt;
 mod syscalls;
 mod ipc;
+mod acpi;
+mod apic;
+mod sched;
+mod percpu;
+mod smp;
+mod pager;
+mod faults;
+mod mmap;
+mod image;
+mod initrd;
+mod manifest;
+mod process;
+mod usercopy;
+mod pcid;
+#[cfg(feature = "kpti")]
+mod kpti;
 
 
-lazy_static! {
-    static ref RENDEZVOUS: alloc::sync::Arc<RwLock<ipc::Rendezvous>> = alloc::sync::Arc::new(RwLock::new(ipc::Rendezvous::Empty));
-}
-
 #[no_mangle]
 pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+    // The per-CPU GDT and TSS live on the heap
+    unsafe { memory::init(boot_info) };
+    usercopy::init();
+    pcid::init();
     println!("Creating Interrupt Descriptor Table");
     gdt::init();
     syscalls::init();
     cpu::init_idt();
-    unsafe { memory::init(boot_info) };
-    unsafe { cpu::PICS.lock().initialize() };
+    #[cfg(feature = "kpti")]
+    kpti::init();
+    cpu::init_interrupt_controllers();
+    smp::init();
     x86_64::instructions::interrupts::enable();
 
     println!("Starting root thread");
-    threads::new_kernel_thread(start_ping_pong);
+    threads::new_kernel_thread(start_init);
     println!("Hello World from the kernel!");
     cpu::hlt_loop();
 }
@@ -50,16 +65,20 @@ fn panic(info: &PanicInfo) -> ! {
     cpu::hlt_loop();
 }
 
-fn start_ping_pong() {
+// Root thread: starts the system described by the boot manifest
+fn start_init() {
     println!("kernel thread started");
-    let thread1 = threads::new_user_thread(include_bytes!("../user_space/ping_pong/target/target/debug/ping_pong"), Vec::from([RENDEZVOUS.clone()]));
-    let thread2 = threads::new_user_thread(include_bytes!("../user_space/ping_pong/target/target/debug/ping_pong"), Vec::from([RENDEZVOUS.clone()]));
-    println!("Threads created. Adding them to the queue");
-    threads::schedule_thread(thread1);
-    threads::schedule_thread(thread2);
-    println!("Ping pong threads created - kernel");
+    for file in initrd::files() {
+        println!("initrd: {} ({} bytes)", file.name, file.data.len());
+    }
+    let text = initrd::find(manifest::MANIFEST_FILE)
+        .and_then(|data| core::str::from_utf8(data).ok())
+        .expect("boot manifest missing from initrd");
+    let result = manifest::Manifest::parse(text).and_then(|manifest| manifest.start());
+    if let Err(e) = result {
+        println!("Boot manifest: {:?}", e);
+    }
     loop {
-        println!("<< 0 >>");
         x86_64::instructions::hlt();
     }
 }
\ No newline at end of file
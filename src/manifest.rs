@@ -0,0 +1,143 @@
+extern crate alloc;
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use spin::RwLock;
+use crate::image::ImageData;
+use crate::initrd;
+use crate::ipc::Rendezvous;
+use crate::process::Handle;
+use crate::threads::{self, LoadError, DEFAULT_PRIORITY};
+
+// Name of the boot manifest in the initrd
+pub const MANIFEST_FILE: &str = "init.manifest";
+
+// Errors carry the line number they were found on, counting from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestError {
+    Syntax(usize),
+    UnknownSection(usize),
+    UnknownKey(usize),
+    InvalidValue(usize),
+    KeyOutsideProgram(usize),
+    DuplicateEndpoint(usize),
+    UnknownEndpoint(usize),
+    ProgramNotFound(usize), // No such file in the initrd
+    Load(usize, LoadError),
+}
+
+pub struct Program<'a> {
+    pub name: &'a str,
+    pub priority: u8,
+    pub handles: Vec<&'a str>, // Endpoint names, in handle order
+    pub args: Vec<&'a str>,    // argv after the program name
+    pub env: Vec<&'a str>,     // NAME=VALUE strings
+    pub privileged: bool,
+    line: usize,
+}
+
+// The system to start at boot: the endpoints to create and the
+// programs to start with them, in order
+pub struct Manifest<'a> {
+    pub endpoints: Vec<&'a str>,
+    pub programs: Vec<Program<'a>>,
+}
+
+fn parse_bool(value: &str, line: usize) -> Result<bool, ManifestError> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(ManifestError::InvalidValue(line))
+    }
+}
+
+impl<'a> Manifest<'a> {
+    // Lines are `[endpoint NAME]` or `[program NAME]` section headers,
+    // or `key = value` settings for the program above them. `#` starts
+    // a comment
+    pub fn parse(text: &'a str) -> Result<Manifest<'a>, ManifestError> {
+        let mut manifest = Manifest { endpoints: Vec::new(), programs: Vec::new() };
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix('[') {
+                let header = header.strip_suffix(']').ok_or(ManifestError::Syntax(number))?;
+                let (kind, name) = header.trim().split_once(char::is_whitespace)
+                    .ok_or(ManifestError::Syntax(number))?;
+                let name = name.trim();
+                match kind {
+                    "endpoint" => {
+                        if manifest.endpoints.contains(&name) {
+                            return Err(ManifestError::DuplicateEndpoint(number));
+                        }
+                        manifest.endpoints.push(name);
+                    }
+                    "program" => manifest.programs.push(Program {
+                        name,
+                        priority: DEFAULT_PRIORITY,
+                        handles: Vec::new(),
+                        args: Vec::new(),
+                        env: Vec::new(),
+                        privileged: false,
+                        line: number
+                    }),
+                    _ => return Err(ManifestError::UnknownSection(number))
+                }
+                continue;
+            }
+
+            let (key, value) = line.split_once('=').ok_or(ManifestError::Syntax(number))?;
+            let (key, value) = (key.trim(), value.trim());
+            let program = manifest.programs.last_mut().ok_or(ManifestError::KeyOutsideProgram(number))?;
+            match key {
+                "priority" => {
+                    program.priority = value.parse().map_err(|_| ManifestError::InvalidValue(number))?;
+                }
+                "privileged" => program.privileged = parse_bool(value, number)?,
+                "handles" => {
+                    for handle in value.split(',').map(str::trim) {
+                        if !manifest.endpoints.contains(&handle) {
+                            return Err(ManifestError::UnknownEndpoint(number));
+                        }
+                        program.handles.push(handle);
+                    }
+                }
+                "args" => program.args.extend(value.split_whitespace()),
+                "env" => {
+                    for variable in value.split(',').map(str::trim) {
+                        if !variable.contains('=') {
+                            return Err(ManifestError::InvalidValue(number));
+                        }
+                        program.env.push(variable);
+                    }
+                }
+                _ => return Err(ManifestError::UnknownKey(number))
+            }
+        }
+        Ok(manifest)
+    }
+
+    // Create the endpoints and start every program. Programs already
+    // started keep running if a later one fails to load
+    pub fn start(&self) -> Result<(), ManifestError> {
+        let endpoints: BTreeMap<&str, Arc<RwLock<Rendezvous>>> = self.endpoints.iter()
+            .map(|&name| (name, Arc::new(RwLock::new(Rendezvous::Empty))))
+            .collect();
+
+        for program in &self.programs {
+            let bin = initrd::find(program.name).ok_or(ManifestError::ProgramNotFound(program.line))?;
+            let handles = program.handles.iter().map(|name| Handle::Rendezvous(endpoints[name].clone())).collect();
+            let mut args = Vec::from([program.name]);
+            args.extend_from_slice(&program.args);
+            let mut thread = threads::new_user_thread(ImageData::Initrd(bin), handles, &args, &program.env)
+                .map_err(|e| ManifestError::Load(program.line, e))?;
+            thread.set_priority(program.priority);
+            thread.set_privileged(program.privileged);
+            println!("Starting {} (thread {})", program.name, thread.id());
+            threads::schedule_thread(thread);
+        }
+        Ok(())
+    }
+}
\ No newline at end of file
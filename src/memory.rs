@@ -1,27 +1,88 @@
 use x86_64::{
-    structures::paging::{Size4KiB, PhysFrame, Page, PageTable, PageTableFlags, OffsetPageTable, Mapper, FrameAllocator, {mapper::MapToError}},
+    structures::paging::{Size4KiB, PhysFrame, Page, PageTable, PageTableFlags, OffsetPageTable, Mapper, FrameAllocator, FrameDeallocator, Translate, {mapper::{MapToError, MappedFrame, TranslateResult}}},
     PhysAddr,
     VirtAddr,
 };
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use bootloader::BootInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::mutex::Mutex;
 use crate::allocator;
+#[cfg(feature = "kpti")]
+use crate::kpti;
+use crate::pcid;
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags};
 
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    free_frames: Vec<PhysFrame>, // Returned frames, handed out first
+}
+
+// Kept for each page table made by create_new_user_pagetable
+#[derive(Clone, Copy)]
+pub struct AddressSpace {
+    pub pcid: u16, // 0 without PCIDs, or if they have run out
+    #[cfg(feature = "kpti")]
+    pub entry_table: u64, // Loaded in user mode
 }
 
 struct MemoryInfo {
     physical_memory_offset: VirtAddr,
     frame_allocator: BootInfoFrameAllocator,
-    kernel_l4_table: &'static mut PageTable
+    kernel_l4_table: &'static mut PageTable,
+    next_mmio: VirtAddr, // Next free address in the device memory window
+    trampoline_frame: Option<PhysFrame>, // Below 1 MiB, for starting other CPUs
+    // Upper half of every user mode page table: only what kpti entry
+    // code needs. See map_entry_range
+    #[cfg(feature = "kpti")]
+    entry_l4_table: &'static mut PageTable,
+    address_spaces: BTreeMap<u64, AddressSpace>, // By page table address
+    // User frames with more than one reference, by address. Any other
+    // frame mapped in user space has one
+    frame_refs: BTreeMap<u64, u64>
 }
 
-static mut MEMORY_INFO: Option<MemoryInfo> = None;
+// The kernel lives in the upper half of the address space:
+//   0xffff_8000_0000_0000  all of physical memory (Cargo.toml)
+//   0xffff_c000_0000_0000  device registers
+//   0xffff_e000_0000_0000  kernel heap (allocator::HEAP_START)
+//   0xffff_ff00_0000_0000  boot info (Cargo.toml)
+//   0xffff_ff80_0000_0000  boot stack (Cargo.toml)
+//   0xffff_ffff_8000_0000  kernel image (build.rs)
+// Every page table shares the kernel's PML4 entries from here on, and
+// user mappings stay below
+const KERNEL_PML4_START: usize = 256;
+
+// Available to software in page table entries. Marks a shared user
+// page mapped read-only, which gets a frame of its own when written.
+// See copy_on_write
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+// Device registers (APIC etc.) are mapped uncached starting here
+const MMIO_START: u64 = 0xffff_c000_0000_0000;
+
+// Shared by all CPUs, so only accessed through with_memory_info. Held
+// during shootdowns
+static MEMORY_INFO: Mutex<Option<MemoryInfo>, pcid::Acknowledge> = Mutex::new(None);
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+// The page table set up by the bootloader, used by kernel threads
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+
+fn with_memory_info<R>(f: impl FnOnce(&mut MemoryInfo) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        f(MEMORY_INFO.lock().as_mut().unwrap())
+    })
+}
 
 pub unsafe fn init(boot_info: &'static BootInfo) {
+    // Needed for NO_EXECUTE in user mappings. Other CPUs copy EFER
+    // from this one when they start
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
     interrupts::without_interrupts(|| {
         let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
         let kernel_l4_table = unsafe {active_level_4_table(physical_memory_offset)};
@@ -30,13 +91,54 @@ pub unsafe fn init(boot_info: &'static BootInfo) {
             BootInfoFrameAllocator::init(&boot_info.memory_map)
         };
 
+        // Usable frames are handed out from the lowest address up, so
+        // grab one in conventional memory before the heap takes them.
+        // Frame zero holds the real mode IVT and BIOS data area
+        let trampoline_frame = (0..2)
+            .filter_map(|_| frame_allocator.allocate_frame())
+            .find(|frame| frame.start_address().as_u64() != 0)
+            .filter(|frame| frame.start_address().as_u64() < 0x100000);
+
         allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
-        
-        unsafe { MEMORY_INFO = Some(MemoryInfo {
+
+        // With every upper half PML4 entry in place, kernel mappings
+        // added later show up in all address spaces. None of them may
+        // be reached from user mode
+        for entry in kernel_l4_table.iter_mut().skip(KERNEL_PML4_START) {
+            if entry.is_unused() {
+                let (_, table_physaddr) = empty_pagetable(&mut frame_allocator, physical_memory_offset);
+                entry.set_addr(PhysAddr::new(table_physaddr), PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+            } else {
+                entry.set_flags(entry.flags() - PageTableFlags::USER_ACCESSIBLE);
+            }
+        }
+
+        // Likewise for the kernel pages user mode page tables can see
+        #[cfg(feature = "kpti")]
+        let entry_l4_table = {
+            let (table_ptr, _) = empty_pagetable(&mut frame_allocator, physical_memory_offset);
+            let table = unsafe { &mut *table_ptr };
+            for entry in table.iter_mut().skip(KERNEL_PML4_START) {
+                let (_, table_physaddr) = empty_pagetable(&mut frame_allocator, physical_memory_offset);
+                entry.set_addr(PhysAddr::new(table_physaddr), PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+            }
+            table
+        };
+
+        PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::SeqCst);
+        let (kernel_l4_frame, _) = x86_64::registers::control::Cr3::read();
+        KERNEL_PAGE_TABLE.store(kernel_l4_frame.start_address().as_u64(), Ordering::SeqCst);
+        *MEMORY_INFO.lock() = Some(MemoryInfo {
             physical_memory_offset,
             frame_allocator,
-            kernel_l4_table
-        }) };
+            kernel_l4_table,
+            next_mmio: VirtAddr::new(MMIO_START),
+            trampoline_frame,
+            #[cfg(feature = "kpti")]
+            entry_l4_table,
+            address_spaces: BTreeMap::new(),
+            frame_refs: BTreeMap::new()
+        });
     });
 }
 
@@ -53,10 +155,69 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     unsafe { &mut *page_table_ptr }
 }
 
-pub fn create_empty_pagetable() -> (*mut PageTable, u64) {
-    let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
-    let level_4_table_frame = memory_info.frame_allocator.allocate_frame().unwrap();
-    let virtual_address = memory_info.physical_memory_offset + level_4_table_frame.start_address().as_u64();
+// Virtual address through which the kernel can access physical memory
+pub fn physical_to_virtual(physaddr: u64) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + physaddr)
+}
+
+pub fn kernel_page_table_physaddr() -> u64 {
+    KERNEL_PAGE_TABLE.load(Ordering::Relaxed)
+}
+
+// Identity map the trampoline frame in the kernel page table, so that
+// a starting CPU can keep running from it after enabling paging.
+// Returns the physical address of the frame
+pub fn map_trampoline() -> Option<u64> {
+    with_memory_info(|memory_info| {
+        let frame = memory_info.trampoline_frame?;
+        let mut mapper = unsafe {
+            OffsetPageTable::new(&mut *memory_info.kernel_l4_table, memory_info.physical_memory_offset)
+        };
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+        match mapper.translate_page(page) {
+            Ok(mapped) if mapped == frame => {}
+            Ok(_) => return None,
+            Err(_) => unsafe {
+                mapper.identity_map(frame,
+                                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                                    &mut memory_info.frame_allocator).ok()?.flush();
+            }
+        }
+        Some(frame.start_address().as_u64())
+    })
+}
+
+// Map a range of device registers into the kernel address space with
+// caching disabled. Returns the virtual address of `physaddr`
+pub fn map_mmio(physaddr: u64, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    with_memory_info(|memory_info| {
+        let mut mapper = unsafe {
+            OffsetPageTable::new(&mut *memory_info.kernel_l4_table, memory_info.physical_memory_offset)
+        };
+
+        let start_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(physaddr));
+        let end_frame = PhysFrame::containing_address(PhysAddr::new(physaddr + size - 1));
+        let start_page = Page::containing_address(memory_info.next_mmio);
+        let flags = PageTableFlags::PRESENT |
+                    PageTableFlags::WRITABLE |
+                    PageTableFlags::WRITE_THROUGH |
+                    PageTableFlags::NO_CACHE;
+
+        for (i, frame) in PhysFrame::range_inclusive(start_frame, end_frame).enumerate() {
+            unsafe {
+                mapper.map_to(start_page + i as u64, frame, flags, &mut memory_info.frame_allocator)?.flush()
+            };
+        }
+        let frame_count = end_frame - start_frame + 1;
+        memory_info.next_mmio += frame_count * 4096;
+
+        Ok(start_page.start_address() + (physaddr - start_frame.start_address().as_u64()))
+    })
+}
+
+fn empty_pagetable(frame_allocator: &mut BootInfoFrameAllocator, physical_memory_offset: VirtAddr) -> (*mut PageTable, u64) {
+    let level_4_table_frame = frame_allocator.allocate_frame().unwrap();
+    let virtual_address = physical_memory_offset + level_4_table_frame.start_address().as_u64();
     let page_table_ptr: *mut PageTable = virtual_address.as_mut_ptr();
 
     // zero out the page table
@@ -66,82 +227,393 @@ pub fn create_empty_pagetable() -> (*mut PageTable, u64) {
     (page_table_ptr, level_4_table_frame.start_address().as_u64())
 }
 
-pub fn create_new_user_pagetable() -> (*mut PageTable, u64) {
-    // Create a new level 4 pagetable
-    let (table_ptr, table_physaddr) = create_empty_pagetable();
-    let table = unsafe {&mut *table_ptr};
-
-    fn copy_pages_rec(physical_memory_offset: VirtAddr, from_table: &PageTable, to_table: &mut PageTable, level: u16) {
-        for (i, entry) in from_table.iter().enumerate() {
-            if !entry.is_unused() {
-                if (level == 1) || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
-                    // Maps a frame, not a page table
-                    to_table[i].set_addr(entry.addr(), entry.flags());
-                } else {
-                    // Create a new table at level - 1
-                    let (new_table_ptr, new_table_physaddr) = create_empty_pagetable();
-                    let to_table_m1 = unsafe {&mut *new_table_ptr};
-
-                    // Point the entry to the new table
-                    to_table[i].set_addr(PhysAddr::new(new_table_physaddr), entry.flags());
-
-                    // Get reference to the input level-1 table
-                    let from_table_m1 = {
-                        let virt = physical_memory_offset + entry.addr().as_u64();
-                        unsafe {& *virt.as_ptr()}
-                    };
-
-                    // Copy level-1 entries
-                    copy_pages_rec(physical_memory_offset, from_table_m1, to_table_m1, level - 1);
+// Free a user page table made by create_new_user_pagetable, together
+// with the frames of user pages. The kernel's tables are shared and
+// kept. The table must not be in use
+pub fn free_user_pagetable(page_table_physaddr: u64) {
+    fn free_rec(memory_info: &mut MemoryInfo, table_physaddr: PhysAddr, level: u16) {
+        let table = unsafe { &*(memory_info.physical_memory_offset + table_physaddr.as_u64()).as_ptr::<PageTable>() };
+        let entries = if level == 4 { KERNEL_PML4_START } else { 512 };
+        for entry in table.iter().take(entries) {
+            if entry.is_unused() {
+                continue;
+            }
+            if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                if level == 1 && entry.flags().contains(PageTableFlags::USER_ACCESSIBLE) {
+                    release(memory_info, PhysFrame::containing_address(entry.addr()));
                 }
+            } else {
+                free_rec(memory_info, entry.addr(), level - 1);
             }
         }
+        unsafe { memory_info.frame_allocator.deallocate_frame(PhysFrame::containing_address(table_physaddr)) };
     }
 
-    // Copy kernel pages
-    let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
-    copy_pages_rec(memory_info.physical_memory_offset, memory_info.kernel_l4_table, table, 4);
+    with_memory_info(|memory_info| {
+        free_rec(memory_info, PhysAddr::new(page_table_physaddr), 4);
+        if let Some(space) = memory_info.address_spaces.remove(&page_table_physaddr) {
+            pcid::free(space.pcid);
+            // Everything below the user mode table is shared
+            #[cfg(feature = "kpti")]
+            unsafe { memory_info.frame_allocator.deallocate_frame(PhysFrame::containing_address(PhysAddr::new(space.entry_table))) };
+        }
+    })
+}
 
-    return (table_ptr, table_physaddr)
+// A page table with no user mappings, and a PCID for it. The kernel
+// half points to the kernel's own tables
+pub fn create_new_user_pagetable() -> (u64, AddressSpace) {
+    with_memory_info(|memory_info| {
+        let (table_ptr, table_physaddr) = empty_pagetable(&mut memory_info.frame_allocator, memory_info.physical_memory_offset);
+        let table = unsafe {&mut *table_ptr};
+        for i in KERNEL_PML4_START..512 {
+            table[i] = memory_info.kernel_l4_table[i].clone();
+        }
+        let space = AddressSpace {
+            pcid: pcid::allocate(),
+            #[cfg(feature = "kpti")]
+            entry_table: create_entry_pagetable(memory_info)
+        };
+        memory_info.address_spaces.insert(table_physaddr, space);
+        (table_physaddr, space)
+    })
 }
 
-pub fn allocate_pages_mapper(
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-    mapper: &mut impl Mapper<Size4KiB>,
-    start_addr: VirtAddr,
-    size: u64,
-    flags: PageTableFlags)
-    -> Result<(), MapToError<Size4KiB>> {
+// The table user mode runs on with kpti. It starts without user
+// mappings too, and map_user_page keeps it in step
+#[cfg(feature = "kpti")]
+fn create_entry_pagetable(memory_info: &mut MemoryInfo) -> u64 {
+    let (table_ptr, table_physaddr) = empty_pagetable(&mut memory_info.frame_allocator, memory_info.physical_memory_offset);
+    let table = unsafe { &mut *table_ptr };
+    for i in KERNEL_PML4_START..512 {
+        table[i] = memory_info.entry_l4_table[i].clone();
+    }
+    table_physaddr
+}
 
-    let page_range = {
-        let end_addr = start_addr + size - 1u64;
-        let start_page = Page::containing_address(start_addr);
-        let end_page = Page::containing_address(end_addr);
-        Page::range_inclusive(start_page, end_page)
-    };
+// Map kernel pages into the upper half of every kpti user mode page
+// table, at the same addresses. Only what the CPU and the entry code
+// touch before switching to the full page table belongs there
+#[cfg(feature = "kpti")]
+pub fn map_entry_range(start: VirtAddr, length: u64) {
+    with_memory_info(|memory_info| {
+        let offset = memory_info.physical_memory_offset;
+        let kernel = unsafe { OffsetPageTable::new(&mut *memory_info.kernel_l4_table, offset) };
+        let mut entry = unsafe { OffsetPageTable::new(&mut *memory_info.entry_l4_table, offset) };
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::containing_address(start + (length - 1));
+        for page in Page::range_inclusive(first, last) {
+            let (frame, flags) = match kernel.translate(page.start_address()) {
+                TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+                _ => panic!("no 4 KiB kernel mapping at {:?}", page.start_address())
+            };
+            // The entry table is never loaded while in the kernel
+            match unsafe { entry.map_to(page, frame, flags, &mut memory_info.frame_allocator) } {
+                Ok(flush) => flush.ignore(),
+                Err(MapToError::PageAlreadyMapped(_)) => {}
+                Err(e) => panic!("entry mapping failed: {:?}", e)
+            }
+        }
+    })
+}
 
-    for page in page_range {
-        let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
-        };
+#[cfg(feature = "kpti")]
+pub fn unmap_entry_range(start: VirtAddr, length: u64) {
+    with_memory_info(|memory_info| {
+        let mut entry = unsafe { OffsetPageTable::new(&mut *memory_info.entry_l4_table, memory_info.physical_memory_offset) };
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::containing_address(start + (length - 1));
+        for page in Page::range_inclusive(first, last) {
+            if let Ok((_, flush)) = entry.unmap(page) {
+                flush.ignore();
+            }
+        }
+        // Every user mode table shares these mappings
+        pcid::invalidate_all();
+        kpti::invalidate_user_tlb();
+    })
+}
+
+// Copy a PML4 entry of a process page table to its user mode table,
+// after map_user_page may have added it
+#[cfg(feature = "kpti")]
+fn sync_entry_table(memory_info: &MemoryInfo, page_table_physaddr: u64, index: usize) {
+    if let Some(space) = memory_info.address_spaces.get(&page_table_physaddr) {
+        let offset = memory_info.physical_memory_offset;
+        let table = unsafe { &*(offset + page_table_physaddr).as_ptr::<PageTable>() };
+        let entry_table = unsafe { &mut *(offset + space.entry_table).as_mut_ptr::<PageTable>() };
+        entry_table[index] = table[index].clone();
     }
+}
 
-    Ok(())
+// After a user mapping is changed or removed, and before its frame is
+// released. invlpg only reaches the current PCID on this CPU, so other
+// CPUs running the address space are made to flush, and other TLB
+// entries for it are flushed when it is next loaded
+fn invalidate(memory_info: &MemoryInfo, page_table_physaddr: u64) {
+    if let Some(space) = memory_info.address_spaces.get(&page_table_physaddr) {
+        pcid::invalidate(space.pcid);
+    }
+    #[cfg(feature = "kpti")]
+    kpti::invalidate_user_tlb();
+    pcid::shootdown(page_table_physaddr);
 }
 
-pub fn allocate_pages(level_4_table: *mut PageTable, start_addr: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
-    let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
+// Frame and flags of the 4 KiB page containing `address`
+pub fn translate(page_table_physaddr: u64, address: VirtAddr) -> Option<(PhysFrame, PageTableFlags)> {
+    with_memory_info(|memory_info| {
+        let table = (memory_info.physical_memory_offset + page_table_physaddr).as_mut_ptr::<PageTable>();
+        let mapper = unsafe { OffsetPageTable::new(&mut *table, memory_info.physical_memory_offset) };
+        match mapper.translate(address) {
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => Some((frame, flags)),
+            _ => None
+        }
+    })
+}
+
+// A zeroed frame for user space, with one reference. It is freed by
+// release_frame, or when the last page mapping it goes
+pub fn allocate_user_frame() -> Option<PhysFrame> {
+    with_memory_info(allocate_zeroed)
+}
 
-    let mut mapper = unsafe {
-        OffsetPageTable::new(&mut *level_4_table, memory_info.physical_memory_offset)
-    };
+// Drop a reference taken by allocate_user_frame
+pub fn release_frame(frame: PhysFrame) {
+    with_memory_info(|memory_info| release(memory_info, frame))
+}
 
-    allocate_pages_mapper(
-        &mut memory_info.frame_allocator,
-        &mut mapper,
-        start_addr, size, flags
-    )
+fn allocate_zeroed(memory_info: &mut MemoryInfo) -> Option<PhysFrame> {
+    let frame = memory_info.frame_allocator.allocate_frame()?;
+    let frame_ptr = (memory_info.physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr::<u8>();
+    unsafe { core::ptr::write_bytes(frame_ptr, 0, 4096) };
+    Some(frame)
+}
+
+fn add_reference(memory_info: &mut MemoryInfo, frame: PhysFrame) {
+    *memory_info.frame_refs.entry(frame.start_address().as_u64()).or_insert(1) += 1;
+}
+
+// The frame is freed with its last reference
+fn release(memory_info: &mut MemoryInfo, frame: PhysFrame) {
+    let address = frame.start_address().as_u64();
+    match memory_info.frame_refs.get_mut(&address) {
+        Some(count) if *count > 2 => *count -= 1,
+        Some(_) => {
+            memory_info.frame_refs.remove(&address);
+        }
+        None => unsafe { memory_info.frame_allocator.deallocate_frame(frame) }
+    }
+}
+
+// Map a user page to `frame`, or to a new zeroed frame if None. The
+// mapping holds a reference to the frame. A page which is already
+// mapped in user space is remapped, or just gets the new flags if no
+// frame is given
+pub fn map_user_page(page_table_physaddr: u64, page: Page<Size4KiB>, frame: Option<PhysFrame>, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    with_memory_info(|memory_info| {
+        let offset = memory_info.physical_memory_offset;
+        let table = (offset + page_table_physaddr).as_mut_ptr::<PageTable>();
+        let mut mapper = unsafe { OffsetPageTable::new(&mut *table, offset) };
+
+        let mut replaced = None;
+        if let TranslateResult::Mapped { frame: existing_frame, flags: existing_flags, .. } = mapper.translate(page.start_address()) {
+            // Kernel mappings are never touched
+            let existing_frame = match existing_frame {
+                MappedFrame::Size4KiB(existing_frame) if existing_flags.contains(PageTableFlags::USER_ACCESSIBLE) => existing_frame,
+                _ => return Err(MapToError::PageAlreadyMapped(PhysFrame::containing_address(existing_frame.start_address())))
+            };
+            match frame {
+                None => {
+                    let flags = cow_flags(memory_info, existing_frame, flags);
+                    unsafe { mapper.update_flags(page, flags).map_err(|_| MapToError::PageAlreadyMapped(existing_frame))?.flush() };
+                    invalidate(memory_info, page_table_physaddr);
+                    return Ok(());
+                }
+                Some(_) => {
+                    mapper.unmap(page).map_err(|_| MapToError::PageAlreadyMapped(existing_frame))?.1.flush();
+                    invalidate(memory_info, page_table_physaddr);
+                    replaced = Some(existing_frame);
+                }
+            }
+        }
+
+        let frame = match frame {
+            Some(frame) => {
+                add_reference(memory_info, frame);
+                frame
+            }
+            None => allocate_zeroed(memory_info).ok_or(MapToError::FrameAllocationFailed)?
+        };
+        // Not before the new reference is taken, as it may be the same
+        // frame
+        if let Some(replaced) = replaced {
+            release(memory_info, replaced);
+        }
+        match unsafe { mapper.map_to(page, frame, flags, &mut memory_info.frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(e) => {
+                release(memory_info, frame);
+                return Err(e);
+            }
+        }
+        #[cfg(feature = "kpti")]
+        sync_entry_table(memory_info, page_table_physaddr, usize::from(page.p4_index()));
+        Ok(())
+    })
+}
+
+// Unmap a user page and drop its reference to the frame. Returns false
+// if the page wasn't mapped
+pub fn unmap_user_page(page_table_physaddr: u64, page: Page<Size4KiB>) -> bool {
+    with_memory_info(|memory_info| {
+        let offset = memory_info.physical_memory_offset;
+        let table = (offset + page_table_physaddr).as_mut_ptr::<PageTable>();
+        let mut mapper = unsafe { OffsetPageTable::new(&mut *table, offset) };
+        match mapper.unmap(page) {
+            Ok((frame, flush)) => {
+                flush.flush();
+                invalidate(memory_info, page_table_physaddr);
+                release(memory_info, frame);
+                true
+            }
+            Err(_) => false
+        }
+    })
+}
+
+// Change the flags of a mapped user page
+pub fn protect_user_page(page_table_physaddr: u64, page: Page<Size4KiB>, flags: PageTableFlags) -> bool {
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    with_memory_info(|memory_info| {
+        let offset = memory_info.physical_memory_offset;
+        let table = (offset + page_table_physaddr).as_mut_ptr::<PageTable>();
+        let mut mapper = unsafe { OffsetPageTable::new(&mut *table, offset) };
+        let flags = match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), .. } => cow_flags(memory_info, frame, flags),
+            _ => return false
+        };
+        match unsafe { mapper.update_flags(page, flags) } {
+            Ok(flush) => {
+                flush.flush();
+                invalidate(memory_info, page_table_physaddr);
+                true
+            }
+            Err(_) => false
+        }
+    })
+}
+
+// Flags for remapping a page of `frame`. A shared frame is only written
+// through a copy
+fn cow_flags(memory_info: &MemoryInfo, frame: PhysFrame, flags: PageTableFlags) -> PageTableFlags {
+    if flags.contains(PageTableFlags::WRITABLE) && memory_info.frame_refs.contains_key(&frame.start_address().as_u64()) {
+        (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+    } else {
+        flags - COPY_ON_WRITE
+    }
+}
+
+// Map every user page of a page table into a new one without user
+// mappings. Writable pages become copy-on-write in both, so that
+// neither sees the other's writes. Returns false if there was no memory
+// for the new tables, which keeps what was mapped so far
+pub fn clone_user_pages(page_table_physaddr: u64, new_page_table_physaddr: u64) -> bool {
+    fn collect_rec(offset: VirtAddr, table_physaddr: PhysAddr, level: u16, base: u64,
+                   pages: &mut Vec<(Page<Size4KiB>, PhysFrame, PageTableFlags)>) {
+        let table = unsafe { &mut *(offset + table_physaddr.as_u64()).as_mut_ptr::<PageTable>() };
+        let entries = if level == 4 { KERNEL_PML4_START } else { 512 };
+        for (index, entry) in table.iter_mut().enumerate().take(entries) {
+            if entry.is_unused() {
+                continue;
+            }
+            let address = base | (index as u64) << (12 + 9 * (level - 1));
+            if level == 1 {
+                let mut flags = entry.flags();
+                if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                    continue;
+                }
+                let page = Page::containing_address(VirtAddr::new(address));
+                if flags.contains(PageTableFlags::WRITABLE) {
+                    flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                    entry.set_flags(flags);
+                    x86_64::instructions::tlb::flush(page.start_address());
+                }
+                pages.push((page, PhysFrame::containing_address(entry.addr()), flags));
+            } else if !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                collect_rec(offset, entry.addr(), level - 1, address, pages);
+            }
+        }
+    }
+
+    with_memory_info(|memory_info| {
+        let offset = memory_info.physical_memory_offset;
+        let mut pages = Vec::new();
+        collect_rec(offset, PhysAddr::new(page_table_physaddr), 4, 0, &mut pages);
+        // Other threads of the parent may still have writable entries in
+        // their TLBs. They must be gone before the child shares the frames
+        invalidate(memory_info, page_table_physaddr);
+
+        let table = (offset + new_page_table_physaddr).as_mut_ptr::<PageTable>();
+        let mut mapper = unsafe { OffsetPageTable::new(&mut *table, offset) };
+        for (page, frame, flags) in pages {
+            match unsafe { mapper.map_to(page, frame, flags, &mut memory_info.frame_allocator) } {
+                // The new table isn't in use yet
+                Ok(flush) => flush.ignore(),
+                Err(_) => return false
+            }
+            add_reference(memory_info, frame);
+            #[cfg(feature = "kpti")]
+            sync_entry_table(memory_info, new_page_table_physaddr, usize::from(page.p4_index()));
+        }
+        true
+    })
+}
+
+// Make a copy-on-write user page writable. A frame which is shared is
+// copied first, and the page moved to the copy. Returns false if the
+// page isn't copy-on-write or there is no memory
+pub fn copy_on_write(page_table_physaddr: u64, page: Page<Size4KiB>) -> bool {
+    with_memory_info(|memory_info| {
+        let offset = memory_info.physical_memory_offset;
+        let table = (offset + page_table_physaddr).as_mut_ptr::<PageTable>();
+        let mut mapper = unsafe { OffsetPageTable::new(&mut *table, offset) };
+        let (frame, flags) = match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. }
+                if flags.contains(COPY_ON_WRITE | PageTableFlags::USER_ACCESSIBLE) => (frame, flags),
+            _ => return false
+        };
+        let flags = (flags | PageTableFlags::WRITABLE) - COPY_ON_WRITE;
+
+        if memory_info.frame_refs.contains_key(&frame.start_address().as_u64()) {
+            let copy = match allocate_zeroed(memory_info) {
+                Some(copy) => copy,
+                None => return false
+            };
+            unsafe {
+                core::ptr::copy_nonoverlapping((offset + frame.start_address().as_u64()).as_ptr::<u8>(),
+                                               (offset + copy.start_address().as_u64()).as_mut_ptr::<u8>(), 4096)
+            };
+            // The page table pages are all there already
+            match mapper.unmap(page) {
+                Ok((_, flush)) => flush.flush(),
+                Err(_) => {
+                    release(memory_info, copy);
+                    return false;
+                }
+            }
+            unsafe { mapper.map_to(page, copy, flags, &mut memory_info.frame_allocator).expect("remap failed").flush() };
+            invalidate(memory_info, page_table_physaddr);
+            release(memory_info, frame);
+        } else {
+            match unsafe { mapper.update_flags(page, flags) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => return false
+            }
+            invalidate(memory_info, page_table_physaddr);
+        }
+        true
+    })
 }
 
 impl BootInfoFrameAllocator {
@@ -149,6 +621,7 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free_frames: Vec::new(),
         }
     }
 
@@ -165,8 +638,17 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free_frames.pop() {
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free_frames.push(frame);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,231 @@
+extern crate alloc;
+use alloc::{collections::BTreeMap, sync::Arc};
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use crate::image::Image;
+use crate::memory;
+use crate::threads::{USER_CODE_END, USER_SPACE_END};
+
+const PAGE_SIZE: u64 = 4096;
+
+// Addresses chosen by the kernel come from this window
+const MMAP_START: u64 = 0x100_0000_0000;
+const MMAP_END: u64 = 0x200_0000_0000;
+
+// Protection bits passed to the mmap and mprotect syscalls
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmapError {
+    InvalidArgument,
+    AlreadyMapped, // Requested range overlaps an existing mapping
+    NotMapped,     // Range isn't part of an anonymous mapping
+    OutOfMemory,
+}
+
+// What the pages of a region are filled from when first touched
+#[derive(Clone)]
+pub enum Source {
+    Anonymous,         // Zeroes, from mmap
+    Stack,             // Zeroes, set up by the loader
+    Image(Arc<Image>), // The program file. The image decides the flags
+}
+
+#[derive(Clone)]
+struct Region {
+    pages: u64,
+    flags: PageTableFlags,
+    source: Source,
+}
+
+// Ranges of user space reserved in an address space, by start address.
+// Pages are mapped on first touch, see fault. Only anonymous regions
+// can be unmapped or protected again
+#[derive(Clone)]
+pub struct Mappings {
+    regions: BTreeMap<u64, Region>,
+}
+
+fn page_flags(prot: u64) -> Result<PageTableFlags, MmapError> {
+    if prot == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        // x86 can't map a page without read access
+        return Err(MmapError::InvalidArgument);
+    }
+    let mut flags = PageTableFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    Ok(flags)
+}
+
+// Page aligned range of `length` bytes starting at `address`, as a
+// start address and page count
+fn page_range(address: u64, length: u64) -> Result<(u64, u64), MmapError> {
+    if address % PAGE_SIZE != 0 || length == 0 {
+        return Err(MmapError::InvalidArgument);
+    }
+    let pages = (length + PAGE_SIZE - 1) / PAGE_SIZE;
+    match address.checked_add(pages * PAGE_SIZE) {
+        Some(end) if end <= USER_SPACE_END => Ok((address, pages)),
+        _ => Err(MmapError::InvalidArgument)
+    }
+}
+
+fn page(address: u64) -> Page<Size4KiB> {
+    Page::containing_address(VirtAddr::new(address))
+}
+
+impl Mappings {
+    pub fn new() -> Mappings {
+        Mappings { regions: BTreeMap::new() }
+    }
+
+    fn overlaps(&self, start: u64, pages: u64) -> bool {
+        let end = start + pages * PAGE_SIZE;
+        self.regions.range(..end).next_back()
+            .map_or(false, |(&region_start, region)| region_start + region.pages * PAGE_SIZE > start)
+    }
+
+    // First gap in the kernel's mmap window which fits `pages`. A fixed
+    // mapping starting below the window may reach into it
+    fn find_free(&self, pages: u64) -> Option<u64> {
+        let mut candidate = self.regions.range(..MMAP_START).next_back()
+            .map_or(MMAP_START, |(&start, region)| u64::max(MMAP_START, start + region.pages * PAGE_SIZE));
+        for (&start, region) in self.regions.range(MMAP_START..MMAP_END) {
+            if start >= candidate + pages * PAGE_SIZE {
+                break;
+            }
+            candidate = u64::max(candidate, start + region.pages * PAGE_SIZE);
+        }
+        if candidate + pages * PAGE_SIZE <= MMAP_END {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+
+    // Reserve zeroed pages at `address`, or at an address chosen by the
+    // kernel if it is 0. Returns the start of the mapping
+    pub fn map(&mut self, page_table_physaddr: u64, address: u64, length: u64, prot: u64) -> Result<u64, MmapError> {
+        let flags = page_flags(prot)?;
+        let (start, pages) = if address == 0 {
+            let (_, pages) = page_range(MMAP_START, length)?;
+            (self.find_free(pages).ok_or(MmapError::OutOfMemory)?, pages)
+        } else {
+            let (start, pages) = page_range(address, length)?;
+            // Program images and stacks live below USER_CODE_END
+            if start < USER_CODE_END || self.overlaps(start, pages) {
+                return Err(MmapError::AlreadyMapped);
+            }
+            (start, pages)
+        };
+
+        // Pages mapped by a pager aren't in any region
+        for i in 0..pages {
+            if memory::translate(page_table_physaddr, VirtAddr::new(start + i * PAGE_SIZE)).is_some() {
+                return Err(MmapError::AlreadyMapped);
+            }
+        }
+        self.regions.insert(start, Region { pages, flags, source: Source::Anonymous });
+        Ok(start)
+    }
+
+    // Reserve pages for the program loader
+    pub fn add(&mut self, start: u64, pages: u64, flags: PageTableFlags, source: Source) -> Result<(), MmapError> {
+        if start % PAGE_SIZE != 0 || pages == 0 || self.overlaps(start, pages) {
+            return Err(MmapError::AlreadyMapped);
+        }
+        self.regions.insert(start, Region { pages, flags, source });
+        Ok(())
+    }
+
+    // Map the page containing `address` if it is in a region. Returns
+    // false if it isn't, or there is no memory
+    pub fn fault(&self, page_table_physaddr: u64, address: u64) -> bool {
+        let region = match self.regions.range(..=address).next_back() {
+            Some((&start, region)) if address < start + region.pages * PAGE_SIZE => region,
+            _ => return false
+        };
+        match &region.source {
+            Source::Anonymous | Source::Stack => {
+                memory::map_user_page(page_table_physaddr, page(address), None, region.flags).is_ok()
+            }
+            Source::Image(image) => image.map_page(page_table_physaddr, address)
+        }
+    }
+
+    // Split regions so that `start` and `end` fall on region boundaries,
+    // and check the whole range is anonymous memory
+    fn split(&mut self, start: u64, end: u64) -> Result<(), MmapError> {
+        for boundary in [start, end] {
+            let found = self.regions.range(..boundary).next_back()
+                .map(|(&region_start, region)| (region_start, region.clone()));
+            if let Some((region_start, region)) = found {
+                let region_end = region_start + region.pages * PAGE_SIZE;
+                if boundary < region_end {
+                    let head = (boundary - region_start) / PAGE_SIZE;
+                    self.regions.insert(region_start, Region { pages: head, ..region.clone() });
+                    self.regions.insert(boundary, Region { pages: region.pages - head, ..region });
+                }
+            }
+        }
+        let mut covered = start;
+        for (&region_start, region) in self.regions.range(start..end) {
+            if region_start != covered || !matches!(region.source, Source::Anonymous) {
+                return Err(MmapError::NotMapped);
+            }
+            covered += region.pages * PAGE_SIZE;
+        }
+        if covered < end {
+            return Err(MmapError::NotMapped);
+        }
+        Ok(())
+    }
+
+    // Unmap a range of mapped pages and drop their frames
+    pub fn unmap(&mut self, page_table_physaddr: u64, address: u64, length: u64) -> Result<(), MmapError> {
+        let (start, pages) = page_range(address, length)?;
+        let end = start + pages * PAGE_SIZE;
+        self.split(start, end)?;
+
+        let removed: alloc::vec::Vec<u64> = self.regions.range(start..end).map(|(&start, _)| start).collect();
+        for region_start in removed {
+            self.regions.remove(&region_start);
+        }
+        for i in 0..pages {
+            memory::unmap_user_page(page_table_physaddr, page(start + i * PAGE_SIZE));
+        }
+        Ok(())
+    }
+
+    // Change the protection of a range of mapped pages
+    pub fn protect(&mut self, page_table_physaddr: u64, address: u64, length: u64, prot: u64) -> Result<(), MmapError> {
+        let flags = page_flags(prot)?;
+        let (start, pages) = page_range(address, length)?;
+        let end = start + pages * PAGE_SIZE;
+        self.split(start, end)?;
+
+        for (_, region) in self.regions.range_mut(start..end) {
+            region.flags = flags;
+        }
+        for i in 0..pages {
+            memory::protect_user_page(page_table_physaddr, page(start + i * PAGE_SIZE), flags);
+        }
+        Ok(())
+    }
+
+    // Drop every mapped frame, when the process goes away
+    pub fn release(&mut self, page_table_physaddr: u64) {
+        for (&start, region) in self.regions.iter() {
+            for i in 0..region.pages {
+                memory::unmap_user_page(page_table_physaddr, page(start + i * PAGE_SIZE));
+            }
+        }
+        self.regions.clear();
+    }
+}
\ No newline at end of file
@@ -0,0 +1,80 @@
+extern crate alloc;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+use crate::arch::arch::{RegisterState, get_cr3};
+use crate::faults::{self, FaultKind, FaultRecord, Resolution, Suspension};
+use crate::ipc::Message;
+use crate::memory;
+use crate::process;
+use crate::threads::{self, Thread, USER_SPACE_END};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagerError {
+    NoSuchThread,    // Not waiting for a page fault reply
+    NotPager,        // Caller doesn't hold the thread's pager handle
+    InvalidAddress,
+}
+
+// Called from the page fault handler for faults in user mode. Blocks
+// the current thread and sends a PageFault message to its pager.
+// Returns the context to switch to, or None if the fault could not be
+// delivered because the thread has no pager
+pub fn handle_fault(context: &RegisterState, address: u64, error_code: u64) -> Option<usize> {
+    let thread = threads::take_current_thread()?;
+    let pager = match thread.pager() {
+        Some(pager) => pager,
+        None => {
+            threads::set_current_thread(thread);
+            return None;
+        }
+    };
+
+    let message = Message::PageFault {
+        thread: thread.id(),
+        address,
+        error_code,
+        rip: context.rip
+    };
+    let record = FaultRecord {
+        thread: thread.id(),
+        kind: FaultKind::PageFault,
+        error_code,
+        rip: context.rip,
+        address,
+        tick: threads::ticks()
+    };
+    faults::suspend(thread, context, Suspension::Pager, record, &pager, message);
+    // Every CPU has an idle thread, so there is always one to run
+    Some(threads::schedule_next(0))
+}
+
+// Map a page into a faulted thread's address space and resume it.
+// `source` is a page of the caller to share, or None for a new zeroed
+// page. Only WRITABLE is taken from `flags`
+pub fn reply(caller: &Thread, thread_id: u64, address: u64, source: Option<u64>, flags: PageTableFlags) -> Result<(), PagerError> {
+    faults::resolve(thread_id, Suspension::Pager, PagerError::NoSuchThread, |thread| {
+        match thread.pager() {
+            Some(pager) if caller.holds(&pager) => {}
+            _ => return Err(PagerError::NotPager)
+        }
+
+        if address >= USER_SPACE_END || source.map_or(false, |source| source >= USER_SPACE_END) {
+            return Err(PagerError::InvalidAddress);
+        }
+        // A shared page can't be made writable if the caller can't write it
+        // The caller's page may not have been touched yet
+        let (frame, allowed_flags) = match source {
+            Some(source) if !process::handle_fault(source, false) => return Err(PagerError::InvalidAddress),
+            Some(source) => match memory::translate(get_cr3() & !0xfff, VirtAddr::new(source)) {
+                Some((frame, source_flags)) if source_flags.contains(PageTableFlags::USER_ACCESSIBLE) => (Some(frame), source_flags),
+                _ => return Err(PagerError::InvalidAddress)
+            },
+            None => (None, PageTableFlags::WRITABLE)
+        };
+
+        let page = Page::containing_address(VirtAddr::new(address));
+        memory::map_user_page(thread.page_table_physaddr(), page, frame, flags & allowed_flags & PageTableFlags::WRITABLE)
+            .map_err(|_| PagerError::InvalidAddress)?;
+        Ok(Resolution::Resume)
+    })
+}
\ No newline at end of file
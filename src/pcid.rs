@@ -0,0 +1,182 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::{RelaxStrategy, RwLock};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use crate::apic;
+use crate::arch::arch::{get_cr3, set_cr3};
+use crate::cpu;
+#[cfg(feature = "kpti")]
+use crate::kpti;
+use crate::percpu::{self, PerCpu};
+
+// Process-context identifiers tag TLB entries with the address space
+// they belong to, so that switching page tables doesn't flush the TLB.
+// Each process gets its own PCID. PCID 0 is used by the kernel's page
+// table, and by processes once the others have run out, and is flushed
+// whenever a different table is loaded with it
+
+// CPUID leaf 1 ECX
+const CPUID_PCID: u32 = 1 << 17;
+// PCIDs handed to processes are below this. kpti tags user mode page
+// tables by setting the next bit
+pub const PCID_COUNT: usize = 2048;
+// Bit 63 of a CR3 value keeps the TLB entries tagged with its PCID
+pub const NOFLUSH: u64 = 1 << 63;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+struct Allocator {
+    next: u16, // Lowest PCID never handed out
+    free: Vec<u16>,
+}
+
+lazy_static! {
+    static ref ALLOCATOR: RwLock<Allocator> = RwLock::new(Allocator { next: 1, free: Vec::new() });
+}
+
+// Called once on the boot CPU, before any process is created
+pub fn init() {
+    let pcid = __cpuid(1).ecx & CPUID_PCID != 0;
+    ENABLED.store(pcid, Ordering::SeqCst);
+    init_cpu();
+}
+
+// CR4.PCIDE needs long mode, so other CPUs set it themselves
+pub fn init_cpu() {
+    if enabled() {
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PCID)) };
+    }
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Returns 0 if there are no PCIDs
+pub fn allocate() -> u16 {
+    if !enabled() {
+        return 0;
+    }
+    let mut allocator = ALLOCATOR.write();
+    if let Some(pcid) = allocator.free.pop() {
+        return pcid;
+    }
+    if usize::from(allocator.next) < PCID_COUNT {
+        allocator.next += 1;
+        return allocator.next - 1;
+    }
+    0
+}
+
+// Entries left in the TLBs are flushed by the next owner
+pub fn free(pcid: u16) {
+    if pcid == 0 {
+        return;
+    }
+    invalidate(pcid);
+    ALLOCATOR.write().free.push(pcid);
+}
+
+// Make every CPU flush the entries tagged with `pcid` the next time it
+// loads it
+pub fn invalidate(pcid: u16) {
+    if pcid == 0 {
+        return;
+    }
+    let (word, bit) = (usize::from(pcid) / 64, 1u64 << (pcid % 64));
+    for index in 0..percpu::count() {
+        if let Some(cpu) = percpu::get(index) {
+            cpu.pcid_fresh[word].fetch_and(!bit, Ordering::SeqCst);
+        }
+    }
+}
+
+// Interrupt the other CPUs which have `page_table` loaded to flush their
+// TLB, and wait until they have. Called after invalidate, so that a CPU
+// loading the table meanwhile flushes it anyway. Once this returns,
+// frames which are no longer mapped can be released
+pub fn shootdown(page_table: u64) {
+    let current = percpu::current();
+    for index in 0..percpu::count() {
+        match percpu::get(index) {
+            Some(cpu) if cpu.index != current.index && cpu.page_table.load(Ordering::SeqCst) == page_table => {
+                cpu.flush_pending.store(true, Ordering::SeqCst);
+                apic::send_ipi(cpu.apic_id, cpu::InterruptIndex::TlbShootdown.as_u8());
+            }
+            _ => {}
+        }
+    }
+    // Another CPU may be waiting for this one at the same time
+    for index in 0..percpu::count() {
+        if let Some(cpu) = percpu::get(index) {
+            while cpu.flush_pending.load(Ordering::SeqCst) {
+                acknowledge();
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+// Flush the TLB entries of the loaded address space if shootdown asked
+// this CPU to
+pub fn acknowledge() {
+    let cpu = percpu::current();
+    if !cpu.flush_pending.load(Ordering::SeqCst) {
+        return;
+    }
+    // Without NOFLUSH, loading CR3 flushes the entries of its PCID
+    set_cr3(get_cr3());
+    #[cfg(feature = "kpti")]
+    kpti::invalidate_user_tlb();
+    cpu.flush_pending.store(false, Ordering::SeqCst);
+}
+
+// Interrupts are off in the kernel, so a CPU spinning on a lock held
+// during a shootdown could never take the interrupt. Locks which can be
+// held then acknowledge it while spinning instead
+pub struct Acknowledge;
+
+impl RelaxStrategy for Acknowledge {
+    fn relax() {
+        acknowledge();
+        core::hint::spin_loop();
+    }
+}
+
+// Only kpti changes mappings shared by every address space
+#[cfg(feature = "kpti")]
+pub fn invalidate_all() {
+    for index in 0..percpu::count() {
+        if let Some(cpu) = percpu::get(index) {
+            for word in cpu.pcid_fresh.iter() {
+                word.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// Load `page_table` tagged with `pcid`. Entries from an earlier run of
+// the address space on this CPU are kept, unless it has been
+// invalidated since
+pub fn load(cpu: &PerCpu, page_table: u64, pcid: u16) {
+    // Before checking freshness, see shootdown
+    cpu.page_table.store(page_table, Ordering::SeqCst);
+    if get_cr3() & !0xfff == page_table {
+        return;
+    }
+    if pcid == 0 {
+        set_cr3(page_table);
+        return;
+    }
+    let (word, bit) = (usize::from(pcid) / 64, 1u64 << (pcid % 64));
+    let fresh = cpu.pcid_fresh[word].fetch_or(bit, Ordering::SeqCst) & bit != 0;
+    set_cr3(page_table | u64::from(pcid) | if fresh { NOFLUSH } else { 0 });
+    // The user mode table shares the freshness of the kernel one
+    #[cfg(feature = "kpti")]
+    if !fresh {
+        kpti::invalidate_user_tlb();
+    }
+}
\ No newline at end of file
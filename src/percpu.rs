@@ -0,0 +1,129 @@
+extern crate alloc;
+use alloc::{boxed::Box, collections::vec_deque::VecDeque, vec::Vec};
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicU64};
+use lazy_static::lazy_static;
+use spin::RwLock;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::gdt::GlobalDescriptorTable;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+use crate::gdt;
+use crate::pcid::PCID_COUNT;
+use crate::threads::Thread;
+
+// Offset of PerCpu::self_ptr from the GS base
+const SELF_OFFSET: usize = size_of::<TaskStateSegment>();
+// Offsets of the page tables the kpti entry code switches between
+pub const KERNEL_CR3_OFFSET: usize = SELF_OFFSET + 8;
+pub const USER_CR3_OFFSET: usize = SELF_OFFSET + 16;
+pub const CR3_NOFLUSH_OFFSET: usize = SELF_OFFSET + 24;
+
+// State owned by one CPU. GS_BASE and KERNEL_GS_BASE both point here,
+// and entry code only swaps GS when coming from user mode, so kernel
+// code can always reach its CPU through GS. Page aligned, so that kpti
+// user page tables can map it without anything else
+#[repr(C, align(4096))]
+pub struct PerCpu {
+    // Must come first: the syscall entry code addresses the
+    // interrupt stack table relative to GS
+    tss: UnsafeCell<TaskStateSegment>,
+    self_ptr: u64,
+    // CR3 values loaded on kernel entry and exit with kpti
+    #[cfg(feature = "kpti")]
+    pub kernel_cr3: AtomicU64,
+    #[cfg(feature = "kpti")]
+    pub user_cr3: AtomicU64,
+    #[cfg(feature = "kpti")]
+    pub cr3_noflush: AtomicU64, // Or'd into user_cr3 once it is loaded
+    // Bit set for each PCID whose TLB entries here are up to date
+    pub pcid_fresh: [AtomicU64; PCID_COUNT / 64],
+    pub page_table: AtomicU64, // Last loaded by pcid::load
+    pub flush_pending: AtomicBool, // Set by pcid::shootdown
+    pub index: usize,
+    pub apic_id: u8,
+    gdt: GlobalDescriptorTable,
+    selectors: Option<gdt::Selectors>,
+    pub current_thread: RwLock<Option<Box<Thread>>>,
+    pub running_queue: RwLock<VecDeque<Box<Thread>>>,
+    // A thread moving to another CPU. Its kernel stack is still in use
+    // until this CPU has switched away, so it is queued there afterwards
+    pub migrating: RwLock<Option<Box<Thread>>>,
+}
+
+// The TSS is only modified by its own CPU, with interrupts disabled
+unsafe impl Sync for PerCpu {}
+
+lazy_static! {
+    static ref CPUS: RwLock<Vec<&'static PerCpu>> = RwLock::new(Vec::new());
+}
+
+// Create the per-CPU block for the calling CPU, load its GDT and TSS
+// and point GS at it
+pub fn init(apic_id: u8) -> &'static PerCpu {
+    let mut cpus = CPUS.write();
+    let cpu: &'static mut PerCpu = Box::leak(Box::new(PerCpu {
+        tss: UnsafeCell::new(gdt::new_tss()),
+        self_ptr: 0,
+        #[cfg(feature = "kpti")]
+        kernel_cr3: AtomicU64::new(0),
+        #[cfg(feature = "kpti")]
+        user_cr3: AtomicU64::new(0),
+        #[cfg(feature = "kpti")]
+        cr3_noflush: AtomicU64::new(0),
+        pcid_fresh: [const { AtomicU64::new(0) }; PCID_COUNT / 64],
+        page_table: AtomicU64::new(0),
+        flush_pending: AtomicBool::new(false),
+        index: cpus.len(),
+        apic_id,
+        gdt: GlobalDescriptorTable::new(),
+        selectors: None,
+        current_thread: RwLock::new(None),
+        running_queue: RwLock::new(VecDeque::new()),
+        migrating: RwLock::new(None),
+    }));
+    cpu.self_ptr = cpu as *const PerCpu as u64;
+
+    let tss: &'static TaskStateSegment = unsafe { &*cpu.tss.get() };
+    let (gdt, selectors) = gdt::new_gdt(tss);
+    cpu.gdt = gdt;
+    cpu.selectors = Some(selectors);
+
+    let cpu: &'static PerCpu = cpu;
+    gdt::load(&cpu.gdt, &selectors);
+    GsBase::write(VirtAddr::new(cpu.self_ptr));
+    KernelGsBase::write(VirtAddr::new(cpu.self_ptr));
+
+    cpus.push(cpu);
+    cpu
+}
+
+pub fn current() -> &'static PerCpu {
+    let ptr: u64;
+    unsafe {
+        asm!("mov {}, gs:[{offset}]", out(reg) ptr, offset = const SELF_OFFSET,
+             options(nostack, readonly, preserves_flags));
+        &*(ptr as *const PerCpu)
+    }
+}
+
+pub fn get(index: usize) -> Option<&'static PerCpu> {
+    CPUS.read().get(index).copied()
+}
+
+pub fn count() -> usize {
+    CPUS.read().len()
+}
+
+impl PerCpu {
+    // Only dereferenced on the CPU that owns it, see the Sync impl
+    pub fn tss_ptr(&self) -> *mut TaskStateSegment {
+        self.tss.get()
+    }
+
+    pub fn selectors(&self) -> gdt::Selectors {
+        self.selectors.unwrap()
+    }
+}
\ No newline at end of file
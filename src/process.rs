@@ -0,0 +1,224 @@
+extern crate alloc;
+use alloc::{collections::BTreeMap, sync::{Arc, Weak}, vec::Vec};
+use lazy_static::lazy_static;
+use spin::{mutex::Mutex, RwLock};
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use crate::arch::arch::{get_cr3, set_cr3};
+use crate::ipc::Rendezvous;
+use crate::memory::{self, AddressSpace, COPY_ON_WRITE};
+use crate::mmap::{Mappings, MmapError, Source};
+use crate::pcid;
+use crate::threads::{USER_HEAP_MAX, USER_HEAP_SIZE, USER_HEAP_START};
+
+// Capability held in a process's handle table
+#[derive(Clone)]
+pub enum Handle {
+    Rendezvous(Arc<RwLock<Rendezvous>>),
+    Thread(u64), // A thread started by this process, by id
+}
+
+// An address space and the handles of the threads running in it.
+// Threads share it through an Arc, so it is freed with the last one
+pub struct Process {
+    page_table_physaddr: u64,
+    address_space: AddressSpace, // PCID, and the user mode table with kpti
+    mappings: Mappings, // Program image, stack and memory from mmap
+    heap_end: u64, // Heap pages below this are mapped on first touch
+    // Held while filling or copying a page, so that threads faulting on
+    // the same page don't both do it
+    filling: Mutex<(), pcid::Acknowledge>,
+    handles: Vec<Handle>,
+}
+
+// Held while changing mappings, so during shootdowns
+pub type ProcessLock = spin::rwlock::RwLock<Process, pcid::Acknowledge>;
+
+lazy_static! {
+    // Shared processes by page table, so that a fault on user memory
+    // can be resolved in the kernel without the current thread
+    static ref PROCESSES: RwLock<BTreeMap<u64, Weak<ProcessLock>>> = RwLock::new(BTreeMap::new());
+}
+
+// Resolve a page fault at a user address in the current address space.
+// See Process::handle_fault
+pub fn handle_fault(address: u64, write: bool) -> bool {
+    let page_table_physaddr = get_cr3() & !0xfff;
+    let process = interrupts::without_interrupts(|| {
+        PROCESSES.read().get(&page_table_physaddr).and_then(Weak::upgrade)
+    });
+    process.map_or(false, |process| process.read().handle_fault(address, write))
+}
+
+impl Process {
+    // A new address space with only the kernel mapped
+    pub fn new(handles: Vec<Handle>) -> Process {
+        let (page_table_physaddr, address_space) = memory::create_new_user_pagetable();
+        Process {
+            page_table_physaddr,
+            address_space,
+            mappings: Mappings::new(),
+            heap_end: USER_HEAP_START + USER_HEAP_SIZE,
+            filling: Mutex::new(()),
+            handles
+        }
+    }
+
+    // Share between threads. Faults in the address space can then be
+    // resolved through handle_fault
+    pub fn into_shared(self) -> Arc<ProcessLock> {
+        let page_table_physaddr = self.page_table_physaddr;
+        let process = Arc::new(ProcessLock::new(self));
+        interrupts::without_interrupts(|| {
+            PROCESSES.write().insert(page_table_physaddr, Arc::downgrade(&process))
+        });
+        process
+    }
+
+    // A copy of the address space with `handles`, sharing every page
+    // copy-on-write. None if there is no memory
+    pub fn fork(&self, handles: Vec<Handle>) -> Option<Process> {
+        let mut child = Process::new(handles);
+        child.mappings = self.mappings.clone();
+        child.heap_end = self.heap_end;
+        // Dropping the child on failure releases what it got so far
+        memory::clone_user_pages(self.page_table_physaddr, child.page_table_physaddr).then_some(child)
+    }
+
+    pub fn page_table_physaddr(&self) -> u64 {
+        self.page_table_physaddr
+    }
+
+    pub fn pcid(&self) -> u16 {
+        self.address_space.pcid
+    }
+
+    #[cfg(feature = "kpti")]
+    pub fn user_page_table_physaddr(&self) -> u64 {
+        self.address_space.entry_table
+    }
+
+    pub fn handle_count(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn handles(&self) -> Vec<Handle> {
+        self.handles.clone()
+    }
+
+    pub fn handle(&self, id: u64) -> Option<Handle> {
+        self.handles.get(id as usize).cloned()
+    }
+
+    pub fn rendezvous(&self, id: u64) -> Option<Arc<RwLock<Rendezvous>>> {
+        match self.handles.get(id as usize) {
+            Some(Handle::Rendezvous(rv)) => Some(rv.clone()),
+            _ => None
+        }
+    }
+
+    // Returns the index of the new handle
+    pub fn add_handle(&mut self, handle: Handle) -> u64 {
+        self.handles.push(handle);
+        (self.handles.len() - 1) as u64
+    }
+
+    // True if one of the handles refers to `rendezvous`
+    pub fn holds(&self, rendezvous: &Arc<RwLock<Rendezvous>>) -> bool {
+        self.handles.iter().any(|handle| match handle {
+            Handle::Rendezvous(rv) => Arc::ptr_eq(rv, rendezvous),
+            _ => false
+        })
+    }
+
+    pub fn holds_thread(&self, thread_id: u64) -> bool {
+        self.handles.iter().any(|handle| matches!(handle, Handle::Thread(id) if *id == thread_id))
+    }
+
+    pub fn mmap(&mut self, address: u64, length: u64, prot: u64) -> Result<u64, MmapError> {
+        // Heap pages may not be mapped yet, but the range is reserved
+        if address != 0 && address < USER_HEAP_START + USER_HEAP_MAX
+            && address.saturating_add(length) > USER_HEAP_START {
+            return Err(MmapError::AlreadyMapped);
+        }
+        self.mappings.map(self.page_table_physaddr, address, length, prot)
+    }
+
+    // Reserve part of the code window for the program loader
+    pub fn reserve(&mut self, start: u64, pages: u64, flags: PageTableFlags, source: Source) -> Result<(), MmapError> {
+        self.mappings.add(start, pages, flags, source)
+    }
+
+    pub fn munmap(&mut self, address: u64, length: u64) -> Result<(), MmapError> {
+        self.mappings.unmap(self.page_table_physaddr, address, length)
+    }
+
+    pub fn mprotect(&mut self, address: u64, length: u64, prot: u64) -> Result<(), MmapError> {
+        self.mappings.protect(self.page_table_physaddr, address, length, prot)
+    }
+
+    pub fn heap_end(&self) -> u64 {
+        self.heap_end
+    }
+
+    // Move the end of the heap and return the new end. Pages are
+    // mapped when first touched, and freed when the heap shrinks
+    pub fn brk(&mut self, new_end: u64) -> Option<u64> {
+        if new_end < USER_HEAP_START || new_end > USER_HEAP_START + USER_HEAP_MAX {
+            return None;
+        }
+        let first_unused = Page::<Size4KiB>::containing_address(VirtAddr::new(new_end + 4095));
+        let last_used = Page::containing_address(VirtAddr::new(u64::max(self.heap_end, USER_HEAP_START + 1) - 1));
+        for page in Page::range_inclusive(first_unused, last_used) {
+            memory::unmap_user_page(self.page_table_physaddr, page);
+        }
+        self.heap_end = new_end;
+        Some(new_end)
+    }
+
+    // Make the page containing `address` accessible from user mode,
+    // and writable if `write` is set. Untouched pages of the heap and
+    // of reserved regions are filled in, and copy-on-write pages are
+    // copied. Returns false if the access isn't allowed or there is no
+    // memory
+    pub fn handle_fault(&self, address: u64, write: bool) -> bool {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(address));
+        interrupts::without_interrupts(|| {
+            let _filling = self.filling.lock();
+            if memory::translate(self.page_table_physaddr, page.start_address()).is_none() {
+                let filled = if address >= USER_HEAP_START && address < self.heap_end {
+                    memory::map_user_page(self.page_table_physaddr, page, None,
+                                          PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).is_ok()
+                } else {
+                    self.mappings.fault(self.page_table_physaddr, address)
+                };
+                if !filled {
+                    return false;
+                }
+            }
+            let flags = match memory::translate(self.page_table_physaddr, page.start_address()) {
+                Some((_, flags)) if flags.contains(PageTableFlags::USER_ACCESSIBLE) => flags,
+                _ => return false
+            };
+            if !write || flags.contains(PageTableFlags::WRITABLE) {
+                return true;
+            }
+            flags.contains(COPY_ON_WRITE) && memory::copy_on_write(self.page_table_physaddr, page)
+        })
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| PROCESSES.write().remove(&self.page_table_physaddr));
+        self.mappings.release(self.page_table_physaddr);
+        self.brk(USER_HEAP_START);
+        // The last thread may have been killed on this CPU, which is
+        // still using its page table
+        if get_cr3() & !0xfff == self.page_table_physaddr {
+            set_cr3(memory::kernel_page_table_physaddr());
+        }
+        memory::free_user_pagetable(self.page_table_physaddr);
+    }
+}
\ No newline at end of file
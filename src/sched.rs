@@ -0,0 +1,88 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// Reserved CPU time is tracked in parts per million of one CPU
+const FULL_UTILIZATION: u64 = 1_000_000;
+
+static RESERVED_UTILIZATION: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub struct SchedParams {
+    pub budget: u64,            // Ticks the thread may run in each period
+    pub period: u64,            // Ticks between replenishments
+    pub deadline: Option<u64>,  // Relative deadline. Enables EDF for the thread
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AdmissionError {
+    InvalidParameters,
+    Overloaded,
+}
+
+// Scheduling context in the style of seL4 MCS. A thread may run for
+// `budget` ticks in every `period`; once the budget is used up the
+// thread is not picked again until the next replenishment
+pub struct SchedContext {
+    params: SchedParams,
+    remaining: u64,
+    period_start: u64,
+    density: u64, // Share of the CPU reserved by admission control
+}
+
+impl SchedContext {
+    // Runs admission control. If `replacing` is given, its reservation
+    // is handed over to the new context instead of being counted twice
+    pub fn admit(params: SchedParams, replacing: Option<&mut SchedContext>, now: u64) -> Result<SchedContext, AdmissionError> {
+        let window = params.deadline.unwrap_or(params.period);
+        if params.budget == 0 || params.budget > params.period
+            || window < params.budget || window > params.period {
+            return Err(AdmissionError::InvalidParameters);
+        }
+
+        // A set of threads is schedulable under EDF if the sum of
+        // budget / min(deadline, period) does not exceed one CPU
+        let density = (params.budget * FULL_UTILIZATION + window - 1) / window;
+        let released = replacing.as_ref().map_or(0, |sc| sc.density);
+        RESERVED_UTILIZATION.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |reserved| {
+            let total = reserved - released + density;
+            if total <= FULL_UTILIZATION { Some(total) } else { None }
+        }).map_err(|_| AdmissionError::Overloaded)?;
+
+        if let Some(old) = replacing {
+            old.density = 0;
+        }
+        Ok(SchedContext {
+            params,
+            remaining: params.budget,
+            period_start: now,
+            density,
+        })
+    }
+
+    // Refill the budget if one or more periods have passed
+    pub fn replenish(&mut self, now: u64) {
+        let elapsed = now - self.period_start;
+        if elapsed >= self.params.period {
+            self.period_start += elapsed - elapsed % self.params.period;
+            self.remaining = self.params.budget;
+        }
+    }
+
+    // Called once for every tick the thread spends running
+    pub fn charge(&mut self) {
+        self.remaining = self.remaining.saturating_sub(1);
+    }
+
+    pub fn depleted(&self) -> bool {
+        self.remaining == 0
+    }
+
+    pub fn absolute_deadline(&self) -> Option<u64> {
+        self.params.deadline.map(|deadline| self.period_start + deadline)
+    }
+}
+
+impl Drop for SchedContext {
+    fn drop(&mut self) {
+        RESERVED_UTILIZATION.fetch_sub(self.density, Ordering::SeqCst);
+    }
+}
\ No newline at end of file
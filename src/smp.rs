@@ -0,0 +1,198 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr0, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Efer;
+use crate::apic;
+use crate::cpu;
+#[cfg(feature = "kpti")]
+use crate::kpti;
+use crate::memory;
+use crate::pcid;
+use crate::percpu;
+use crate::syscalls;
+use crate::threads;
+
+const AP_STACK_SIZE: usize = 4096 * 4;
+// EFER.LMA is set by the processor, not written
+const EFER_LMA: u64 = 1 << 10;
+
+// Real mode entry point for the application processors. It is copied
+// to a page below 1 MiB and runs at offset 0 of that page. The
+// smp_trampoline_* fields are patched before each processor is started
+global_asm!(
+    ".global smp_trampoline_start",
+    ".global smp_trampoline_end",
+    ".global smp_trampoline_gdtr",
+    ".global smp_trampoline_cr0",
+    ".global smp_trampoline_cr3",
+    ".global smp_trampoline_cr4",
+    ".global smp_trampoline_efer",
+    ".global smp_trampoline_target",
+    ".global smp_trampoline_stack",
+    ".global smp_trampoline_entry",
+    ".code16",
+    "smp_trampoline_start:",
+    "cli",
+    "mov ax, cs",
+    "mov ds, ax",
+    "lgdt [smp_trampoline_gdtr_offset]",
+    "mov eax, dword ptr [smp_trampoline_cr4_offset]",
+    "mov cr4, eax",
+    "mov eax, dword ptr [smp_trampoline_cr3_offset]",
+    "mov cr3, eax",
+    "mov ecx, 0xc0000080",
+    "mov eax, dword ptr [smp_trampoline_efer_offset]",
+    "xor edx, edx",
+    "wrmsr",
+    // Protected mode and paging at once puts us in long mode
+    "mov eax, dword ptr [smp_trampoline_cr0_offset]",
+    "mov cr0, eax",
+    // Far jump with a 32-bit offset into the 64-bit code segment
+    ".byte 0x66, 0xea",
+    "smp_trampoline_target:",
+    ".long 0",
+    ".word 0x08",
+
+    ".code64",
+    "smp_trampoline_long_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov rsp, [rip + smp_trampoline_stack]",
+    "mov rax, [rip + smp_trampoline_entry]",
+    "call rax",
+    "2:",
+    "hlt",
+    "jmp 2b",
+
+    ".align 8",
+    "smp_trampoline_gdt:",
+    ".quad 0",
+    ".quad 0x00af9a000000ffff", // 64-bit code
+    ".quad 0x00cf92000000ffff", // data
+    "smp_trampoline_gdtr:",
+    ".word 23",
+    ".long 0",
+    ".align 8",
+    "smp_trampoline_cr0: .quad 0",
+    "smp_trampoline_cr3: .quad 0",
+    "smp_trampoline_cr4: .quad 0",
+    "smp_trampoline_efer: .quad 0",
+    "smp_trampoline_stack: .quad 0",
+    "smp_trampoline_entry: .quad 0",
+    "smp_trampoline_end:",
+    // Real mode addresses relative to ds, which is the trampoline page.
+    // A memory operand can only refer to one symbol
+    ".equ smp_trampoline_gdtr_offset, smp_trampoline_gdtr - smp_trampoline_start",
+    ".equ smp_trampoline_cr0_offset, smp_trampoline_cr0 - smp_trampoline_start",
+    ".equ smp_trampoline_cr3_offset, smp_trampoline_cr3 - smp_trampoline_start",
+    ".equ smp_trampoline_cr4_offset, smp_trampoline_cr4 - smp_trampoline_start",
+    ".equ smp_trampoline_efer_offset, smp_trampoline_efer - smp_trampoline_start",
+);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_end: u8;
+    static smp_trampoline_gdtr: u8;
+    static smp_trampoline_cr0: u8;
+    static smp_trampoline_cr3: u8;
+    static smp_trampoline_cr4: u8;
+    static smp_trampoline_efer: u8;
+    static smp_trampoline_target: u8;
+    static smp_trampoline_stack: u8;
+    static smp_trampoline_entry: u8;
+}
+
+// Set by each processor once it can take interrupts
+static AP_READY: AtomicBool = AtomicBool::new(false);
+
+// Offset of a trampoline label from the start of the trampoline
+fn offset_of(label: &u8) -> u64 {
+    label as *const u8 as u64 - unsafe { &smp_trampoline_start as *const u8 as u64 }
+}
+
+// Start every other processor listed in the MADT. Must be called on
+// the boot processor after the local APIC is set up
+pub fn init() {
+    threads::new_idle_thread(idle);
+
+    let processors = apic::processors();
+    if processors.len() < 2 {
+        return;
+    }
+    let base = match memory::map_trampoline() {
+        Some(base) => base,
+        None => {
+            println!("No memory for the SMP trampoline");
+            return;
+        }
+    };
+
+    let trampoline = memory::physical_to_virtual(base).as_mut_ptr::<u8>();
+    let write = |label: &u8, value: u64| unsafe {
+        ptr::write_unaligned(trampoline.add(offset_of(label) as usize) as *mut u64, value)
+    };
+    unsafe {
+        let length = offset_of(&smp_trampoline_end) as usize;
+        ptr::copy_nonoverlapping(&smp_trampoline_start as *const u8, trampoline, length);
+
+        // The GDTR base is 32 bits and the jump target a 32-bit
+        // offset, so only patch those bytes
+        let gdt = base + offset_of(&smp_trampoline_gdtr) - 24;
+        ptr::write_unaligned(trampoline.add(offset_of(&smp_trampoline_gdtr) as usize + 2) as *mut u32, gdt as u32);
+        let target = base + offset_of(&smp_trampoline_target) + 6;
+        ptr::write_unaligned(trampoline.add(offset_of(&smp_trampoline_target) as usize) as *mut u32, target as u32);
+
+        write(&smp_trampoline_cr0, Cr0::read_raw());
+        write(&smp_trampoline_cr3, Cr3::read().0.start_address().as_u64());
+        // CR4.PCIDE can only be set in long mode, so ap_entry sets it
+        // through pcid::init_cpu once paging is on
+        write(&smp_trampoline_cr4, Cr4::read_raw() & !Cr4Flags::PCID.bits());
+        write(&smp_trampoline_efer, Efer::read_raw() & !EFER_LMA);
+        write(&smp_trampoline_entry, ap_entry as *const () as u64);
+    }
+
+    let bsp = apic::local_apic_id();
+    for apic_id in processors.into_iter().filter(|&id| id != bsp) {
+        let stack: &'static mut [u8] = Vec::leak(alloc::vec![0; AP_STACK_SIZE]);
+        unsafe { write(&smp_trampoline_stack, stack.as_ptr() as u64 + AP_STACK_SIZE as u64) };
+
+        AP_READY.store(false, Ordering::SeqCst);
+        apic::start_ap(apic_id, base);
+        // Give it up to 100 ms to come up
+        let started = (0..100).any(|_| {
+            if AP_READY.load(Ordering::SeqCst) {
+                return true;
+            }
+            apic::delay_us(1000);
+            false
+        });
+        if !started {
+            println!("CPU with APIC ID {} did not start", apic_id);
+        }
+    }
+    println!("{} CPUs running", percpu::count());
+}
+
+extern "C" fn ap_entry() -> ! {
+    percpu::init(apic::local_apic_id());
+    cpu::init_idt();
+    syscalls::init();
+    pcid::init_cpu();
+    #[cfg(feature = "kpti")]
+    kpti::init_cpu();
+    apic::init_local(cpu::InterruptIndex::Timer.as_u8());
+    threads::new_idle_thread(idle);
+    AP_READY.store(true, Ordering::SeqCst);
+    interrupts::enable();
+    cpu::hlt_loop();
+}
+
+fn idle() {
+    cpu::hlt_loop();
+}
\ No newline at end of file
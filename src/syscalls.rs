@@ -1,28 +1,79 @@
 use core::arch::asm;
-use core::{slice, str};
-use crate::threads;
+use core::str;
+extern crate alloc;
+use alloc::{boxed::Box, string::String, vec::Vec};
+use x86_64::structures::paging::PageTableFlags;
+use crate::image::ImageData;
+use crate::initrd;
+use crate::process::Handle;
+use crate::threads::{self, LoadError, Thread};
 use crate::cpu;
+use crate::faults::{self, Resolution, Suspension};
 use crate::gdt;
+use crate::percpu;
 use crate::arch::arch::RegisterState;
 use crate::ipc::Message;
+use crate::mmap::MmapError;
+use crate::pager::{self, PagerError};
+use crate::usercopy::{self, UserCopyError};
+use crate::sched::{AdmissionError, SchedParams};
 
 const MSR_STAR: usize = 0xc0000081;
 const MSR_LSTAR: usize = 0xc0000082;
 const MSR_FMASK: usize = 0xc0000084;
 const MSR_KERNEL_GS_BASE: usize = 0xC0000102;
 pub const SYSCALL_ERROR_INVALID_HANDLE: u64 = 3;
+pub const SYSCALL_ERROR_INVALID_ARGUMENT: u64 = 4;
+pub const SYSCALL_ERROR_ADMISSION: u64 = 5;
+pub const SYSCALL_ERROR_PERMISSION: u64 = 6;
+pub const SYSCALL_ERROR_ADDRESS_IN_USE: u64 = 7;
+pub const SYSCALL_ERROR_NOT_MAPPED: u64 = 8;
+pub const SYSCALL_ERROR_OUT_OF_MEMORY: u64 = 9;
 const SYSCALL_KERNEL_STACK_OFFSET: u64 = 1024;
 
+// Program to start with the spawn syscall. The image is an ELF file
+// in the caller's memory or, if `image_length` is 0, the initrd file
+// called `name`. `handles` are indices into the caller's handle table
+// which become handles 0, 1, ... of the new thread. `args` and `env`
+// are NUL terminated strings back to back, args starting with argv[0]
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct SpawnRequest {
+    pub image: *const u8,
+    pub image_length: u64,
+    pub name: *const u8,
+    pub name_length: u64,
+    pub handles: *const u64,
+    pub handle_count: u64,
+    pub args: *const u8,
+    pub args_length: u64,
+    pub env: *const u8,
+    pub env_length: u64,
+}
+
 #[naked]
+#[cfg_attr(feature = "kpti", link_section = "entry_text")]
 extern "C" fn handle_syscall() {
     unsafe {
         asm!(
+            // Kernel GS for calls from user mode, which is anything
+            // in the lower half. It stays loaded until we return to
+            // user mode. With kpti, so does the kernel page table. rsp
+            // is the only free register
+            "test rcx, rcx",
+            "js 3f",
             "swapgs",
+            ".if {kpti}",
+            "mov gs:{tss_temp}, rsp",
+            "mov rsp, gs:[{kernel_cr3}]",
+            "mov cr3, rsp",
+            "mov rsp, gs:{tss_temp}",
+            ".endif",
+            "3:",
             "mov gs:{tss_temp}, rsp",
             "mov rsp, gs:{tss_timer}",
             "sub rsp, {ks_offset}",
             "push gs:{tss_temp}",
-            "swapgs",
 
             "push r11",
             "sub rsp, 8",
@@ -70,10 +121,17 @@ extern "C" fn handle_syscall() {
             "add rsp, 24",
             "pop rsp",
 
-            "cmp rcx, {user_code_start}",
-            "jl 9f",
-            "cmp rcx, {user_code_end}",
-            "jge 9f",
+            "test rcx, rcx",
+            "js 9f",
+            ".if {kpti}",
+            "mov gs:{tss_temp}, rsp",
+            "mov rsp, gs:[{user_cr3}]",
+            "mov cr3, rsp",
+            "mov rsp, gs:[{cr3_noflush}]",
+            "or gs:[{user_cr3}], rsp",
+            "mov rsp, gs:{tss_temp}",
+            ".endif",
+            "swapgs",
             "sysretq", // back to userspace
             
             "9:",
@@ -85,8 +143,10 @@ extern "C" fn handle_syscall() {
             tss_timer = const(0x24 + gdt::TIMER_INTERRUPT_INDEX * 8),
             tss_temp = const(0x24 + gdt::SYSCALL_TEMP_INDEX * 8),
             ks_offset = const(SYSCALL_KERNEL_STACK_OFFSET),
-            user_code_start = const(threads::USER_CODE_START),
-            user_code_end = const(threads::USER_CODE_END),
+            kpti = const cfg!(feature = "kpti") as u8,
+            kernel_cr3 = const percpu::KERNEL_CR3_OFFSET,
+            user_cr3 = const percpu::USER_CR3_OFFSET,
+            cr3_noflush = const percpu::CR3_NOFLUSH_OFFSET,
             options(noreturn),
         );
     }
@@ -96,9 +156,10 @@ extern "C" fn syscall_router(context_ptr: *mut RegisterState, syscall_id: u64, a
 
     let context = unsafe{&mut *context_ptr};
 
-    // Set the CS and SS segment selectors
+    // Set the CS and SS segment selectors. Like handle_syscall, treat
+    // any caller in the lower half as user mode
     let (code_selector, data_selector) =
-    if context.rip < threads::USER_CODE_START {
+    if context.rip >= threads::USER_SPACE_END {
         gdt::get_kernel_segments()
     } else {
         gdt::get_user_segments()
@@ -110,8 +171,25 @@ extern "C" fn syscall_router(context_ptr: *mut RegisterState, syscall_id: u64, a
         0 => hello_world(),
         1 => sys_write(arg1 as *mut u8, arg2 as usize),
         2 => ipc_write(context_ptr, arg1, arg2),
-        3 => ipc_read(context_ptr, arg1),
+        3 => ipc_read(context_ptr, arg1, arg2),
         4 => sys_yield(context_ptr),
+        5 => sys_thread_stats(context, arg1 as *mut threads::ThreadStats, arg2 as usize),
+        6 => sys_set_sched(context_ptr, arg1, arg2, arg3),
+        7 => sys_set_timeslice(context_ptr, arg1, arg2),
+        8 => sys_set_affinity(context_ptr, arg1),
+        9 => sys_set_pager(context_ptr, arg1),
+        10 => sys_pager_reply(context_ptr, arg1, arg2, arg3),
+        11 => sys_fault_log(context, arg1 as *mut faults::FaultRecord, arg2 as usize),
+        12 => sys_set_exception_handler(context_ptr, arg1),
+        13 => sys_exception_state(context_ptr, arg1, arg2 as *mut RegisterState),
+        14 => sys_exception_reply(context_ptr, arg1, arg2 as *const RegisterState, arg3),
+        15 => sys_mmap(context_ptr, arg1, arg2, arg3),
+        16 => sys_munmap(context_ptr, arg1, arg2),
+        17 => sys_mprotect(context_ptr, arg1, arg2, arg3),
+        18 => sys_brk(context_ptr, arg1),
+        19 => sys_spawn(context_ptr, arg1 as *const SpawnRequest),
+        20 => sys_thread_create(context_ptr, arg1, arg2, arg3),
+        21 => sys_fork(context_ptr, arg1 as *const u64, arg2),
         _ => println!("Unknown syscall {:?} {} {} {}", context_ptr, syscall_id, arg1, arg2)
     }
 }
@@ -125,7 +203,7 @@ pub fn init() {
             "wrmsr"
         );
         asm!("xor rdx, rdx",
-            "mov rax, 0x300",
+            "mov rax, 0x40300", // Clear IF, TF and AC
             "wrmsr",
             in("rcx") MSR_FMASK
         );
@@ -151,11 +229,22 @@ pub fn init() {
     }
 }
 
-fn ipc_read(context_ptr: *mut RegisterState, handle: u64) {
+// `registers` is a buffer for the registers of an Exception message,
+// or 0
+fn ipc_read(context_ptr: *mut RegisterState, handle: u64, registers: u64) {
     // Extract the current thread
     if let Some(mut thread) = threads::take_current_thread() {
         let current_id = thread.id();
         thread.set_context(context_ptr);
+        threads::update_stats(current_id, |stats| stats.ipc_receives += 1);
+
+        let buffer_end = registers.checked_add(core::mem::size_of::<RegisterState>() as u64);
+        if registers % 8 != 0 || buffer_end.map_or(true, |end| end > threads::USER_SPACE_END) {
+            thread.return_error(SYSCALL_ERROR_INVALID_ARGUMENT);
+            threads::set_current_thread(thread);
+            return;
+        }
+        thread.set_registers_buffer(registers);
 
         // Get the Rendezvous and call
         if let Some(rdv) = thread.rendezvous(handle) {
@@ -196,6 +285,7 @@ fn ipc_write(context_ptr: *mut RegisterState, handle: u64, data: u64) {
     if let Some(mut thread) = threads::take_current_thread() {
         let current_id = thread.id();
         thread.set_context(context_ptr);
+        threads::update_stats(current_id, |stats| stats.ipc_sends += 1);
 
         // Get the Rendezvous and call
         if let Some(rdv) = thread.rendezvous(handle) {
@@ -235,10 +325,381 @@ fn sys_yield(context_ptr: *mut RegisterState) {
     cpu::launch_thread(next_stack);
 }
 
+// Copies up to max_records ThreadStats into the user buffer and
+// returns the number written in rax
+fn sys_thread_stats(context: &mut RegisterState, ptr: *mut threads::ThreadStats, max_records: usize) {
+    let records = threads::thread_stats(max_records);
+    context.rax = usercopy::copy_to_user(ptr, &records).map_or(0, |_| records.len() as u64);
+}
+
+// Copies up to max_records of the most recent FaultRecords into the
+// user buffer and returns the number written in rax
+fn sys_fault_log(context: &mut RegisterState, ptr: *mut faults::FaultRecord, max_records: usize) {
+    let records = faults::fault_log(max_records);
+    context.rax = usercopy::copy_to_user(ptr, &records).map_or(0, |_| records.len() as u64);
+}
+
+// Give the calling thread a budget of `budget` ticks every `period`
+// ticks. A non-zero deadline selects EDF scheduling, and a zero
+// budget makes the thread best-effort again
+fn sys_set_sched(context_ptr: *mut RegisterState, budget: u64, period: u64, deadline: u64) {
+    if let Some(mut thread) = threads::take_current_thread() {
+        thread.set_context(context_ptr);
+        let params = if budget == 0 {
+            None
+        } else {
+            Some(SchedParams {
+                budget,
+                period,
+                deadline: if deadline == 0 { None } else { Some(deadline) }
+            })
+        };
+        match thread.set_sched_params(params) {
+            Ok(()) => thread.return_error(0),
+            Err(AdmissionError::InvalidParameters) => thread.return_error(SYSCALL_ERROR_INVALID_ARGUMENT),
+            Err(AdmissionError::Overloaded) => thread.return_error(SYSCALL_ERROR_ADMISSION),
+        }
+        threads::set_current_thread(thread);
+    }
+}
+
+// Set the timeslice in ticks of the thread with the given id. Only
+// privileged threads such as the root task, or holders of a handle to
+// the thread, may do this
+fn sys_set_timeslice(context_ptr: *mut RegisterState, thread_id: u64, ticks: u64) {
+    if let Some(mut thread) = threads::take_current_thread() {
+        thread.set_context(context_ptr);
+        let allowed = thread.is_privileged()
+            || thread.process().map_or(false, |process| process.read().holds_thread(thread_id));
+        let error = if !allowed {
+            SYSCALL_ERROR_PERMISSION
+        } else if thread.id() == thread_id {
+            thread.set_timeslice(ticks);
+            0
+        } else if threads::set_timeslice(thread_id, ticks) {
+            0
+        } else {
+            SYSCALL_ERROR_INVALID_ARGUMENT
+        };
+        thread.return_error(error);
+        threads::set_current_thread(thread);
+    }
+}
+
+// Pin the calling thread to a CPU, or let it run on any CPU if the
+// argument is u64::MAX. The move happens at the next reschedule
+fn sys_set_affinity(context_ptr: *mut RegisterState, cpu_index: u64) {
+    if let Some(mut thread) = threads::take_current_thread() {
+        thread.set_context(context_ptr);
+        if cpu_index == u64::MAX {
+            thread.set_affinity(None);
+            thread.return_error(0);
+        } else if (cpu_index as usize) < percpu::count() {
+            thread.set_affinity(Some(cpu_index as usize));
+            thread.return_error(0);
+        } else {
+            thread.return_error(SYSCALL_ERROR_INVALID_ARGUMENT);
+        }
+        threads::set_current_thread(thread);
+    }
+}
+
+// Send the calling thread's page faults to the given handle, or handle
+// them in the kernel again if the handle is u64::MAX
+fn sys_set_pager(context_ptr: *mut RegisterState, handle: u64) {
+    if let Some(mut thread) = threads::take_current_thread() {
+        thread.set_context(context_ptr);
+        if handle == u64::MAX {
+            thread.set_pager(None);
+            thread.return_error(0);
+        } else if let Some(rdv) = thread.rendezvous(handle) {
+            thread.set_pager(Some(rdv));
+            thread.return_error(0);
+        } else {
+            thread.return_error(SYSCALL_ERROR_INVALID_HANDLE);
+        }
+        threads::set_current_thread(thread);
+    }
+}
+
+// Send exceptions of the calling thread to the given handle, or kill
+// the thread on exceptions again if the handle is u64::MAX
+fn sys_set_exception_handler(context_ptr: *mut RegisterState, handle: u64) {
+    if let Some(mut thread) = threads::take_current_thread() {
+        thread.set_context(context_ptr);
+        if handle == u64::MAX {
+            thread.set_exception_handler(None);
+            thread.return_error(0);
+        } else if let Some(rdv) = thread.rendezvous(handle) {
+            thread.set_exception_handler(Some(rdv));
+            thread.return_error(0);
+        } else {
+            thread.return_error(SYSCALL_ERROR_INVALID_HANDLE);
+        }
+        threads::set_current_thread(thread);
+    }
+}
+
+// Copy the registers of a thread waiting for the caller to handle its
+// exception into the user buffer
+fn sys_exception_state(context_ptr: *mut RegisterState, thread_id: u64, registers: *mut RegisterState) {
+    if let Some(mut thread) = threads::take_current_thread() {
+        thread.set_context(context_ptr);
+        let state = faults::inspect(thread_id, Suspension::ExceptionHandler, |faulted| {
+            match faulted.exception_handler() {
+                Some(handler) if thread.holds(&handler) => Ok(faulted.registers()),
+                _ => Err(SYSCALL_ERROR_PERMISSION)
+            }
+        });
+        let error = match state {
+            Some(Ok(state)) => usercopy::copy_to_user(registers, &[state]).err().map_or(0, copy_error),
+            Some(Err(error)) => error,
+            None => SYSCALL_ERROR_INVALID_ARGUMENT
+        };
+        thread.return_error(error);
+        threads::set_current_thread(thread);
+    }
+}
+
+// Finish handling an exception. Action 0 resumes the thread, with its
+// registers replaced from the buffer unless it is null. Action 1 kills it
+fn sys_exception_reply(context_ptr: *mut RegisterState, thread_id: u64, registers: *const RegisterState, action: u64) {
+    if let Some(mut thread) = threads::take_current_thread() {
+        thread.set_context(context_ptr);
+        let result = faults::resolve(thread_id, Suspension::ExceptionHandler, SYSCALL_ERROR_INVALID_ARGUMENT, |faulted| {
+            match faulted.exception_handler() {
+                Some(handler) if thread.holds(&handler) => {}
+                _ => return Err(SYSCALL_ERROR_PERMISSION)
+            }
+            match action {
+                0 => {
+                    if !registers.is_null() {
+                        let registers = usercopy::read(registers).map_err(copy_error)?;
+                        if !faulted.set_user_registers(&registers) {
+                            return Err(SYSCALL_ERROR_INVALID_ARGUMENT);
+                        }
+                    }
+                    Ok(Resolution::Resume)
+                }
+                1 => Ok(Resolution::Terminate),
+                _ => Err(SYSCALL_ERROR_INVALID_ARGUMENT)
+            }
+        });
+        thread.return_error(result.err().unwrap_or(0));
+        threads::set_current_thread(thread);
+    }
+}
+
+// Resolve a page fault: map a page at `address` in the faulted
+// thread and let it run again. `source` is a page of the caller to
+// share, or 0 for a new zeroed page. The low bits of `address` are
+// page table flags, of which only WRITABLE is used
+fn sys_pager_reply(context_ptr: *mut RegisterState, thread_id: u64, address: u64, source: u64) {
+    if let Some(mut thread) = threads::take_current_thread() {
+        thread.set_context(context_ptr);
+        let flags = PageTableFlags::from_bits_truncate(address & 0xfff);
+        let source = if source == 0 { None } else { Some(source) };
+        let error = match pager::reply(&thread, thread_id, address & !0xfff, source, flags) {
+            Ok(()) => 0,
+            Err(PagerError::NoSuchThread) => SYSCALL_ERROR_INVALID_ARGUMENT,
+            Err(PagerError::NotPager) => SYSCALL_ERROR_PERMISSION,
+            Err(PagerError::InvalidAddress) => SYSCALL_ERROR_INVALID_ARGUMENT,
+        };
+        thread.return_error(error);
+        threads::set_current_thread(thread);
+    }
+}
+
+fn mmap_error(error: MmapError) -> u64 {
+    match error {
+        MmapError::InvalidArgument => SYSCALL_ERROR_INVALID_ARGUMENT,
+        MmapError::AlreadyMapped => SYSCALL_ERROR_ADDRESS_IN_USE,
+        MmapError::NotMapped => SYSCALL_ERROR_NOT_MAPPED,
+        MmapError::OutOfMemory => SYSCALL_ERROR_OUT_OF_MEMORY,
+    }
+}
+
+// Map `length` bytes of zeroed memory at `address`, or wherever the
+// kernel likes if it is 0. `prot` is a mask of mmap::PROT_* bits.
+// The address of the mapping is returned in rdi
+fn sys_mmap(context_ptr: *mut RegisterState, address: u64, length: u64, prot: u64) {
+    if let Some(mut thread) = threads::take_current_thread() {
+        thread.set_context(context_ptr);
+        match thread.mmap(address, length, prot) {
+            Ok(start) => thread.return_value(0, start),
+            Err(error) => thread.return_value(mmap_error(error), 0),
+        }
+        threads::set_current_thread(thread);
+    }
+}
+
+fn sys_munmap(context_ptr: *mut RegisterState, address: u64, length: u64) {
+    if let Some(mut thread) = threads::take_current_thread() {
+        thread.set_context(context_ptr);
+        let error = thread.munmap(address, length).err().map_or(0, mmap_error);
+        thread.return_error(error);
+        threads::set_current_thread(thread);
+    }
+}
+
+fn sys_mprotect(context_ptr: *mut RegisterState, address: u64, length: u64, prot: u64) {
+    if let Some(mut thread) = threads::take_current_thread() {
+        thread.set_context(context_ptr);
+        let error = thread.mprotect(address, length, prot).err().map_or(0, mmap_error);
+        thread.return_error(error);
+        threads::set_current_thread(thread);
+    }
+}
+
+// Set the end of the heap, or just return it if the argument is 0.
+// The end of the heap is returned in rdi
+fn sys_brk(context_ptr: *mut RegisterState, new_end: u64) {
+    if let Some(mut thread) = threads::take_current_thread() {
+        thread.set_context(context_ptr);
+        match thread.process() {
+            Some(process) => {
+                let mut process = process.write();
+                let result = if new_end == 0 { Some(process.heap_end()) } else { process.brk(new_end) };
+                match result {
+                    Some(end) => thread.return_value(0, end),
+                    None => thread.return_value(SYSCALL_ERROR_INVALID_ARGUMENT, process.heap_end()),
+                }
+            }
+            None => thread.return_value(SYSCALL_ERROR_INVALID_ARGUMENT, 0),
+        }
+        threads::set_current_thread(thread);
+    }
+}
+
+fn copy_error(error: UserCopyError) -> u64 {
+    match error {
+        UserCopyError::BadAddress => SYSCALL_ERROR_INVALID_ARGUMENT,
+        UserCopyError::OutOfMemory => SYSCALL_ERROR_OUT_OF_MEMORY,
+    }
+}
+
+// Split a buffer of NUL terminated strings
+fn string_list(bytes: &[u8]) -> Option<Vec<&str>> {
+    match bytes.split_last() {
+        None => Some(Vec::new()),
+        Some((0, strings)) => strings.split(|&b| b == 0).map(|s| str::from_utf8(s).ok()).collect(),
+        Some(_) => None
+    }
+}
+
+fn spawn(caller: &Thread, request: *const SpawnRequest) -> Result<Box<Thread>, u64> {
+    let request = usercopy::read(request).map_err(copy_error)?;
+    let image = if request.image_length != 0 {
+        ImageData::Copied(usercopy::read_vec(request.image, request.image_length).map_err(copy_error)?)
+    } else {
+        let name = usercopy::read_vec(request.name, request.name_length).map_err(copy_error)?;
+        ImageData::Initrd(str::from_utf8(&name).ok()
+            .and_then(initrd::find)
+            .ok_or(SYSCALL_ERROR_INVALID_ARGUMENT)?)
+    };
+
+    let handles = usercopy::read_vec(request.handles, request.handle_count)
+        .map_err(copy_error)?
+        .iter()
+        .map(|&index| caller.handle(index).ok_or(SYSCALL_ERROR_INVALID_HANDLE))
+        .collect::<Result<Vec<Handle>, u64>>()?;
+    let args = usercopy::read_vec(request.args, request.args_length).map_err(copy_error)?;
+    let env = usercopy::read_vec(request.env, request.env_length).map_err(copy_error)?;
+    let args = string_list(&args).ok_or(SYSCALL_ERROR_INVALID_ARGUMENT)?;
+    let env = string_list(&env).ok_or(SYSCALL_ERROR_INVALID_ARGUMENT)?;
+
+    threads::new_user_thread(image, handles, &args, &env).map_err(|error| match error {
+        LoadError::OutOfMemory => SYSCALL_ERROR_OUT_OF_MEMORY,
+        _ => SYSCALL_ERROR_INVALID_ARGUMENT
+    })
+}
+
+// Give the caller's process a handle to a new thread and start it.
+// The handle is returned in rdi
+fn start_child(thread: &Thread, child: Box<Thread>) {
+    match thread.process() {
+        Some(process) => {
+            let handle = process.write().add_handle(Handle::Thread(child.id()));
+            threads::schedule_thread(child);
+            thread.return_value(0, handle);
+        }
+        None => thread.return_value(SYSCALL_ERROR_INVALID_ARGUMENT, 0),
+    }
+}
+
+// Start a program in a new address space. Only privileged threads may
+// do this
+fn sys_spawn(context_ptr: *mut RegisterState, request: *const SpawnRequest) {
+    if let Some(mut thread) = threads::take_current_thread() {
+        thread.set_context(context_ptr);
+        let result = if thread.is_privileged() {
+            spawn(&thread, request)
+        } else {
+            Err(SYSCALL_ERROR_PERMISSION)
+        };
+        match result {
+            Ok(child) => start_child(&thread, child),
+            Err(error) => thread.return_value(error, 0),
+        }
+        threads::set_current_thread(thread);
+    }
+}
+
+// Start a thread in the caller's address space at `entry_point`, with
+// `stack_pointer` and `argument` in rdi
+fn sys_thread_create(context_ptr: *mut RegisterState, entry_point: u64, stack_pointer: u64, argument: u64) {
+    if let Some(mut thread) = threads::take_current_thread() {
+        thread.set_context(context_ptr);
+        match threads::new_thread_in_process(&thread, entry_point, stack_pointer, argument) {
+            Some(child) => start_child(&thread, child),
+            None => thread.return_value(SYSCALL_ERROR_INVALID_ARGUMENT, 0),
+        }
+        threads::set_current_thread(thread);
+    }
+}
+
+// Run a copy of the calling thread in a copy of its address space.
+// Both return from here with 0 in rax: the caller with the id of the
+// new thread in rdi, the new thread with 0, which is never an id. The
+// caller's process also gets a handle to the new thread. The new
+// process gets the caller's handles at the `handle_count` indexes in
+// `handles`, or all of them if `handles` is null
+fn sys_fork(context_ptr: *mut RegisterState, handles: *const u64, handle_count: u64) {
+    if let Some(mut thread) = threads::take_current_thread() {
+        // The new thread starts from the registers saved here
+        thread.set_context(context_ptr);
+        match fork(&thread, handles, handle_count) {
+            Ok(child_id) => thread.return_value(0, child_id),
+            Err(error) => thread.return_value(error, 0),
+        }
+        threads::set_current_thread(thread);
+    }
+}
+
+fn fork(caller: &Thread, handles: *const u64, handle_count: u64) -> Result<u64, u64> {
+    let process = caller.process().ok_or(SYSCALL_ERROR_INVALID_ARGUMENT)?;
+    let handles = if handles.is_null() {
+        process.read().handles()
+    } else {
+        usercopy::read_vec(handles, handle_count)
+            .map_err(copy_error)?
+            .iter()
+            .map(|&index| caller.handle(index).ok_or(SYSCALL_ERROR_INVALID_HANDLE))
+            .collect::<Result<Vec<Handle>, u64>>()?
+    };
+    let child = threads::fork_thread(caller, handles).ok_or(SYSCALL_ERROR_OUT_OF_MEMORY)?;
+    let child_id = child.id();
+    child.return_value(0, 0);
+    process.write().add_handle(Handle::Thread(child_id));
+    threads::schedule_thread(child);
+    Ok(child_id)
+}
+
 extern "C" fn sys_write(ptr: *mut u8, len: usize) {
-    let u8_slice = unsafe {slice::from_raw_parts(ptr, len)};
+    let text = usercopy::read_vec(ptr as *const u8, len as u64).ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok());
 
-    if let Ok(s) = str::from_utf8(u8_slice) {
+    if let Some(s) = text {
         println!("Write '{}'", s);
     } else {
         println!("Write failed");
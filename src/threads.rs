@@ -2,57 +2,165 @@ extern crate alloc;
 use alloc::vec::Vec;
 use spin::RwLock;
 use lazy_static::lazy_static;
-use alloc::{boxed::Box, collections::vec_deque::VecDeque, sync::Arc};
+use alloc::{boxed::Box, collections::{vec_deque::VecDeque, BTreeMap}, sync::Arc};
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU64, Ordering};
 use x86_64::instructions::interrupts;
 use x86_64::VirtAddr;
 use x86_64::structures::paging::PageTableFlags;
+use crate::apic;
+use crate::cpu;
 use crate::gdt;
+#[cfg(feature = "kpti")]
+use crate::kpti;
+use crate::image::{Image, ImageData, Segment};
 use crate::memory;
-use crate::arch::arch::{RegisterState, INTERRUPT_CONTEXT_SIZE, set_cr3, get_cr3};
+use crate::pcid;
+use crate::mmap::{MmapError, Source};
+use crate::process::{Handle, Process, ProcessLock};
+use crate::percpu::{self, PerCpu};
+use crate::arch::arch::{RegisterState, INTERRUPT_CONTEXT_SIZE};
 use crate::ipc::{Message,Rendezvous};
+use crate::sched::{AdmissionError, SchedContext, SchedParams};
+use crate::syscalls::SYSCALL_ERROR_INVALID_ARGUMENT;
 
 const KERNEL_STACK_SIZE: usize = 4096 * 2;
 const USER_STACK_SIZE: usize = 4096 * 5;
 pub const USER_CODE_START: u64 = 0x2000000;
 pub const USER_CODE_END: u64 = 0x5000000;
 const USER_STACK_START: u64 = 0x3000000;
-const USER_HEAP_START: u64 = 0x280_0060_0000;
-const USER_HEAP_SIZE: u64 = 4 * 1024 * 1024; 
+pub const USER_HEAP_START: u64 = 0x280_0060_0000;
+pub const USER_HEAP_SIZE: u64 = 4 * 1024 * 1024; 
+// Limit for growing the heap with brk
+pub const USER_HEAP_MAX: u64 = 1024 * 1024 * 1024;
+pub const DEFAULT_TIMESLICE: u64 = 10; // Timer ticks
+pub const DEFAULT_PRIORITY: u8 = 100;
+// End of the lower canonical half
+pub const USER_SPACE_END: u64 = 0x8000_0000_0000;
+// Arithmetic flags, TF and DF. TF traps to the #DB handler after each
+// instruction. Interrupts are always enabled
+const USER_RFLAGS: u64 = 0xdd5;
+// Room left for the program below argv, envp and the auxiliary vector
+const USER_STACK_RESERVE: u64 = 4096;
+
+// Auxiliary vector entry types, from the System V ABI
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+// Number of handles the thread starts with. Not a standard type
+pub const AT_HANDLES: u64 = 0x1000;
+
+// Page aligned, so that kpti user page tables can map a thread's
+// kernel stack without the heap around it
+#[repr(C, align(4096))]
+struct KernelStack([u8; KERNEL_STACK_SIZE]);
 
 pub struct Thread {
     id: u64,
-    handles: Vec<Arc<RwLock<Rendezvous>>>,
-    kernel_stack: Vec<u8>,
+    process: Option<Arc<ProcessLock>>, // None for kernel threads
+    pager: Option<Arc<RwLock<Rendezvous>>>, // Receives this thread's page faults
+    exception_handler: Option<Arc<RwLock<Rendezvous>>>, // Receives other exceptions
+    registers_buffer: u64, // Where a received Exception message's registers go, or 0
+    kernel_stack: Box<MaybeUninit<KernelStack>>,
     user_stack: Vec<u8>,
     kernel_stack_end: u64,
     user_stack_end: u64,
     context: u64, // Address of register state on kernel stack
-    page_table_physaddr: u64
+    page_table_physaddr: u64, // The process's, or 0 for kernel threads
+    pcid: u16,
+    #[cfg(feature = "kpti")]
+    user_page_table_physaddr: u64, // Loaded in user mode
+    blocked_since: Option<u64>, // Tick at which the thread started waiting
+    sched: Option<SchedContext>, // None for best-effort threads
+    timeslice: u64, // Ticks the thread runs before being rotated out
+    slice_remaining: u64,
+    privileged: bool, // Allowed to configure other threads
+    priority: u8, // Higher runs first among best-effort threads
+    affinity: Option<usize>, // CPU the thread is pinned to
+    cpu: Option<usize>, // CPU the thread last ran on
+    idle: bool // Only run when a CPU has nothing else to do
+}
+
+// Scheduler accounting for a single thread. This is also the
+// record layout copied out to user space by the thread_stats syscall
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct ThreadStats {
+    pub id: u64,
+    pub ticks: u64,
+    pub context_switches: u64,
+    pub ipc_sends: u64,
+    pub ipc_receives: u64,
+    pub blocked_ticks: u64,
 }
 
 lazy_static! {
-    static ref RUNNING_QUEUE: RwLock<VecDeque<Box<Thread>>> = RwLock::new(VecDeque::new());
-    static ref CURRENT_THREAD: RwLock<Option<Box<Thread>>> = RwLock::new(None);
     static ref THREAD_COUNTER: RwLock<u64> = RwLock::new(0);
+    // Kept outside of Thread so that blocked threads, which are owned
+    // by a Rendezvous, can still be reported
+    static ref THREAD_STATS: RwLock<BTreeMap<u64, ThreadStats>> = RwLock::new(BTreeMap::new());
 }
 
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
 pub fn new_kernel_thread(function: fn()->()) {
+    schedule_thread(kernel_thread(function));
+}
+
+// Each CPU has an idle thread pinned to it, so there is always
+// something to run
+pub fn new_idle_thread(function: fn()->()) {
+    let mut thread = kernel_thread(function);
+    thread.idle = true;
+    thread.affinity = Some(percpu::current().index);
+    schedule_thread(thread);
+}
+
+// Returns the stack and its end address. The interrupt stack table
+// points there while the thread runs, so kpti maps it for user mode
+fn new_kernel_stack() -> (Box<MaybeUninit<KernelStack>>, u64) {
+    let stack = Box::<KernelStack>::new_uninit();
+    let stack_start = VirtAddr::from_ptr(stack.as_ptr());
+    #[cfg(feature = "kpti")]
+    memory::map_entry_range(stack_start, KERNEL_STACK_SIZE as u64);
+    (stack, (stack_start + KERNEL_STACK_SIZE).as_u64())
+}
+
+fn kernel_thread(function: fn()->()) -> Box<Thread> {
     let new_thread = {
-        let kernel_stack = Vec::with_capacity(KERNEL_STACK_SIZE);
-        let kernel_stack_end = (VirtAddr::from_ptr(kernel_stack.as_ptr()) + KERNEL_STACK_SIZE).as_u64();
+        let (kernel_stack, kernel_stack_end) = new_kernel_stack();
         let user_stack = Vec::with_capacity(USER_STACK_SIZE);
         let user_stack_end = (VirtAddr::from_ptr(user_stack.as_ptr()) + USER_STACK_SIZE).as_u64();
         let context = kernel_stack_end - INTERRUPT_CONTEXT_SIZE as u64;
 
         Box::new(Thread {
             id: next_id(),
-            handles: Vec::new(),
+            process: None,
+            pager: None,
+            exception_handler: None,
+            registers_buffer: 0,
             kernel_stack,
             user_stack,
             kernel_stack_end,
             user_stack_end,
             context,
-            page_table_physaddr: 0})
+            page_table_physaddr: 0,
+            pcid: 0,
+            #[cfg(feature = "kpti")]
+            user_page_table_physaddr: 0,
+            blocked_since: None,
+            sched: None,
+            timeslice: DEFAULT_TIMESLICE,
+            slice_remaining: DEFAULT_TIMESLICE,
+            privileged: true,
+            priority: DEFAULT_PRIORITY,
+            affinity: None,
+            cpu: None,
+            idle: false})
     };
 
     // Set context registers
@@ -65,150 +173,596 @@ pub fn new_kernel_thread(function: fn()->()) {
     context.cs = code_selector.0 as u64;
     context.ss = data_selector.0 as u64;
 
-    // Add Thread to RUNNING_QUEUE
-    interrupts::without_interrupts(|| {
-        RUNNING_QUEUE.write().push_back(new_thread);
-    });
+    new_thread
 }
 
 pub fn schedule_next(context_addr: usize) -> usize {
-    let mut running_queue = RUNNING_QUEUE.write();
-    let mut current_thread = CURRENT_THREAD.write();
-
-    if let Some(mut thread) = current_thread.take() {
-        thread.context = context_addr as u64;
-        thread.page_table_physaddr = get_cr3();
-        running_queue.push_back(thread);
-    }
-    
-    // Get the next thread in the queue
-    *current_thread = running_queue.pop_front();
-    match current_thread.as_ref() {
-        Some(thread) => {
-            // Set the kernel stack for the next interrupt
-            gdt::set_interrupt_stack_table(
-              gdt::TIMER_INTERRUPT_INDEX as usize,
-              VirtAddr::new(thread.kernel_stack_end));
-            if thread.page_table_physaddr != 0 {
-                set_cr3(thread.page_table_physaddr);
+    let cpu = percpu::current();
+    finish_migration();
+    {
+        let mut running_queue = cpu.running_queue.write();
+        let mut current_thread = cpu.current_thread.write();
+
+        let previous_id = current_thread.as_ref().map(|thread| thread.id);
+        if let Some(mut thread) = current_thread.take() {
+            thread.context = context_addr as u64;
+            if thread.affinity.map_or(false, |index| index != cpu.index) {
+                *cpu.migrating.write() = Some(thread);
+            } else {
+                running_queue.push_back(thread);
+            }
+        }
+
+        // Get the next thread in the queue. The idle thread is always
+        // runnable, so it takes over when every other thread has used
+        // up its budget
+        *current_thread = next_thread_index(&mut running_queue, ticks())
+            .and_then(|index| running_queue.remove(index));
+        match current_thread.as_mut() {
+            Some(thread) => {
+                thread.slice_remaining = thread.timeslice;
+                thread.cpu = Some(cpu.index);
+                if previous_id != Some(thread.id) {
+                    update_stats(thread.id, |stats| stats.context_switches += 1);
+                }
+                // Set the kernel stack for the next interrupt
+                gdt::set_interrupt_stack_table(
+                  gdt::TIMER_INTERRUPT_INDEX as usize,
+                  VirtAddr::new(thread.kernel_stack_end));
+                // Kernel threads use the kernel's own page table, so a
+                // process's table isn't left loaded after it is freed
+                let page_table = match thread.page_table_physaddr {
+                    0 => memory::kernel_page_table_physaddr(),
+                    page_table => page_table
+                };
+                pcid::load(cpu, page_table, thread.pcid);
+                #[cfg(feature = "kpti")]
+                if thread.user_page_table_physaddr != 0 {
+                    kpti::set_page_tables(cpu, page_table, thread.user_page_table_physaddr, thread.pcid);
+                }
+                // println!("Switching to thread {}", thread.id());
+                // Point the stack to the new context
+                thread.context as usize
+              },
+            None => 0  // Timer handler won't modify stack
+        }
+    }
+}
+
+// Queue the thread left by schedule_next on its new CPU. Must be called
+// on a different stack from the one schedule_next switched away from
+pub fn finish_migration() {
+    let thread = percpu::current().migrating.write().take();
+    if let Some(thread) = thread {
+        schedule_thread(thread);
+    }
+}
+
+// Threads with a deadline are run earliest deadline first, then other
+// threads with a scheduling context, then best-effort threads by
+// priority. Otherwise the queue order gives round robin. Threads which have used
+// up their budget are skipped until it is replenished
+fn next_thread_index(queue: &mut VecDeque<Box<Thread>>, now: u64) -> Option<usize> {
+    let mut best: Option<(usize, (u8, u64))> = None;
+    for (index, thread) in queue.iter_mut().enumerate() {
+        if let Some(key) = thread.sched_key(now) {
+            if best.map_or(true, |(_, best_key)| key < best_key) {
+                best = Some((index, key));
             }
-            // println!("Switching to thread {}", thread.id());
-            // Point the stack to the new context
-            thread.context as usize
-          },
-        None => 0  // Timer handler won't modify stack
+        }
     }
+    best.map(|(index, _)| index)
 }
 
-pub fn new_user_thread(bin: &[u8], handles: Vec<Arc<RwLock<Rendezvous>>>) -> Box<Thread> {
+// Page table flags for an ELF segment: text is read-only and
+// executable, everything else is not executable
+fn segment_flags(p_flags: u32) -> PageTableFlags {
+    use elf::abi::{PF_W, PF_X};
+    let mut flags = PageTableFlags::empty();
+    if p_flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if p_flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+// Write into the address space of a process being loaded, through the
+// physical memory mapping. Pages are filled in first. Returns false if
+// there is no memory
+fn write_user(process: &Process, address: u64, bytes: &[u8]) -> bool {
+    let mut written = 0;
+    while written < bytes.len() {
+        let current = address + written as u64;
+        if !process.handle_fault(current, true) {
+            return false;
+        }
+        let frame = match memory::translate(process.page_table_physaddr(), VirtAddr::new(current)) {
+            Some((frame, _)) => frame,
+            None => return false
+        };
+        let page_offset = current % 4096;
+        let length = usize::min(bytes.len() - written, (4096 - page_offset) as usize);
+        let destination = memory::physical_to_virtual(frame.start_address().as_u64() + page_offset);
+        unsafe {
+            core::ptr::copy_nonoverlapping(bytes[written..].as_ptr(), destination.as_mut_ptr::<u8>(), length);
+        }
+        written += length;
+    }
+    true
+}
+
+// Why new_user_thread couldn't load a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    BadMagic,
+    Malformed,           // Truncated or inconsistent headers
+    NotExecutable,       // Neither ET_EXEC nor ET_DYN
+    WrongClass,          // Not a 64-bit ELF file
+    WrongMachine,        // Not built for x86-64
+    SegmentOutOfRange,   // PT_LOAD segment outside USER_CODE_START..USER_CODE_END
+    OverlappingSegments,
+    EntryOutsideCode,    // Entry point isn't in an executable segment
+    UnsupportedRelocation, // Only R_X86_64_RELATIVE is handled
+    ArgumentsTooLong,    // argv, envp and auxv don't fit on the stack
+    OutOfMemory,
+}
+
+// Where position independent executables are loaded
+const PIE_LOAD_BASE: u64 = USER_CODE_START;
+const ELF64_PHDR_SIZE: u64 = 56;
+
+// A checked ELF file. Addresses are still relative to `base`
+struct Program {
+    entry_point: u64,
+    base: u64,
+    phdr: Option<u64>, // Program headers, if a segment loads them
+    phnum: u64,
+    segments: Vec<elf::segment::ProgramHeader>, // PT_LOAD only, sorted by address
+    relocations: Vec<elf::relocation::Rela>,
+}
+
+// Check the headers and collect what is needed to load the program
+fn parse_program(bin: &[u8]) -> Result<Program, LoadError> {
     use elf::endian::AnyEndian;
     use elf::ElfBytes;
-    use elf::abi::PT_LOAD;
+    use elf::abi::{EM_X86_64, ET_DYN, ET_EXEC, PF_X, PT_LOAD, R_X86_64_RELATIVE};
+    use elf::file::Class;
 
     // Verify headers are for an ELF file
     const ELF_HEADERS: [u8; 4] = [0x7f, b'E', b'L', b'F'];
-    if bin[0..4] != ELF_HEADERS {
-        println!("Invalid ELF file");
-    }
-
-    let file = ElfBytes::<AnyEndian>::minimal_parse(bin).unwrap();
-    let entry_point: u64 = file.ehdr.e_entry;
-    let (user_page_table_ptr, user_page_table_physaddr) = memory::create_new_user_pagetable();
-    set_cr3(user_page_table_physaddr);
-
-    for segment in file.segments().unwrap().iter() {
-        // println!("Segment: {:?}", segment);
-        if segment.p_type != PT_LOAD {
-            continue;
-        }
-        let segment_address = segment.p_vaddr as u64;
-        let segment_size = segment.p_memsz as u64;
-        let start_address = VirtAddr::new(segment_address);
-        let end_address = start_address + segment_size;
-        if (start_address < VirtAddr::new(USER_CODE_START))
-            || (end_address >= VirtAddr::new(USER_CODE_END)) {
-                println!("ELF segment outside allowed range");
+    if bin.len() < 4 || bin[0..4] != ELF_HEADERS {
+        return Err(LoadError::BadMagic);
+    }
+
+    let file = ElfBytes::<AnyEndian>::minimal_parse(bin).map_err(|_| LoadError::Malformed)?;
+    if file.ehdr.class != Class::ELF64 {
+        return Err(LoadError::WrongClass);
+    }
+    if file.ehdr.e_machine != EM_X86_64 {
+        return Err(LoadError::WrongMachine);
+    }
+
+    let mut segments: Vec<_> = file.segments().ok_or(LoadError::Malformed)?.iter()
+        .filter(|segment| segment.p_type == PT_LOAD)
+        .collect();
+    segments.sort_by_key(|segment| segment.p_vaddr);
+
+    // Position independent executables are linked at 0 and moved to
+    // the user code window
+    let base = match file.ehdr.e_type {
+        ET_EXEC => 0,
+        ET_DYN => {
+            let lowest = segments.first().map_or(0, |segment| segment.p_vaddr & !0xfff);
+            PIE_LOAD_BASE.checked_sub(lowest).ok_or(LoadError::SegmentOutOfRange)?
+        }
+        _ => return Err(LoadError::NotExecutable)
+    };
+
+    let mut relocations = Vec::new();
+    if file.ehdr.e_type == ET_DYN {
+        let section = file.section_header_by_name(".rela.dyn").map_err(|_| LoadError::Malformed)?;
+        if let Some(section) = section {
+            for rela in file.section_data_as_relas(&section).map_err(|_| LoadError::Malformed)? {
+                if rela.r_type != R_X86_64_RELATIVE {
+                    return Err(LoadError::UnsupportedRelocation);
+                }
+                relocations.push(rela);
             }
+        }
+    }
+
+    let stack_start = USER_STACK_START;
+    let stack_end = USER_STACK_START + USER_STACK_SIZE as u64;
+    for segment in segments.iter() {
+        let file_end = segment.p_offset.checked_add(segment.p_filesz);
+        if segment.p_filesz > segment.p_memsz || file_end.map_or(true, |end| end > bin.len() as u64) {
+            return Err(LoadError::Malformed);
+        }
+        let start = base.checked_add(segment.p_vaddr).ok_or(LoadError::SegmentOutOfRange)?;
+        let end = start.checked_add(segment.p_memsz).ok_or(LoadError::SegmentOutOfRange)?;
+        if start < USER_CODE_START || end > USER_CODE_END || (start < stack_end && end > stack_start) {
+            return Err(LoadError::SegmentOutOfRange);
+        }
+    }
+    for pair in segments.windows(2) {
+        let end = pair[0].p_vaddr.checked_add(pair[0].p_memsz).ok_or(LoadError::SegmentOutOfRange)?;
+        if end > pair[1].p_vaddr {
+            return Err(LoadError::OverlappingSegments);
+        }
+    }
+
+    // Where the program headers end up in memory, for AT_PHDR
+    let phoff = file.ehdr.e_phoff;
+    let phnum = file.ehdr.e_phnum as u64;
+    let phdr_end = phnum.checked_mul(ELF64_PHDR_SIZE).and_then(|size| phoff.checked_add(size));
+    let phdr = segments.iter()
+        .find(|segment| phoff >= segment.p_offset
+              && phdr_end.map_or(false, |end| end <= segment.p_offset + segment.p_filesz))
+        .map(|segment| segment.p_vaddr + (phoff - segment.p_offset));
+
+    let entry_point = file.ehdr.e_entry;
+    let entry_in_code = segments.iter().any(|segment| {
+        segment.p_flags & PF_X != 0
+            && entry_point >= segment.p_vaddr
+            && entry_point < segment.p_vaddr + segment.p_memsz
+    });
+    if !entry_in_code {
+        return Err(LoadError::EntryOutsideCode);
+    }
+    Ok(Program { entry_point, base, phdr, phnum, segments, relocations })
+}
 
-        // Allocate memory in the pagetable
-        if memory::allocate_pages(user_page_table_ptr,
-                            VirtAddr::new(segment_address),
-                            segment_size,
-                            PageTableFlags::PRESENT |
-                            PageTableFlags::WRITABLE |
-                            PageTableFlags::USER_ACCESSIBLE).is_err() {
-            println!("Could not allocate memory");
+// Reserve the program's segments and stack in a new address space.
+// Pages are filled from the file, with relocations applied, when first
+// touched
+fn load_program(data: ImageData, process: &mut Process) -> Result<Program, LoadError> {
+    let program = parse_program(data.bytes())?;
+    let segments: Vec<Segment> = program.segments.iter().map(|segment| Segment {
+        address: program.base + segment.p_vaddr,
+        memory_size: segment.p_memsz,
+        file_offset: segment.p_offset,
+        file_size: segment.p_filesz,
+        flags: segment_flags(segment.p_flags),
+    }).collect();
+
+    // R_X86_64_RELATIVE: the word at the offset becomes base + addend
+    let mut relocations = Vec::with_capacity(program.relocations.len());
+    for rela in program.relocations.iter() {
+        let address = program.base.wrapping_add(rela.r_offset);
+        let in_segment = segments.iter().any(|segment| {
+            address >= segment.address
+                && address.checked_add(8).map_or(false, |end| end <= segment.address + segment.memory_size)
+        });
+        if !in_segment {
+            return Err(LoadError::Malformed);
         }
+        relocations.push((address, program.base.wrapping_add(rela.r_addend as u64)));
+    }
+
+    let image = Arc::new(Image::new(data, segments, relocations));
+    for (start, pages) in image.ranges() {
+        process.reserve(start, pages, PageTableFlags::empty(), Source::Image(image.clone()))
+            .map_err(|_| LoadError::SegmentOutOfRange)?;
+    }
+    process.reserve(USER_STACK_START, USER_STACK_SIZE as u64 / 4096,
+                    PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE, Source::Stack)
+        .map_err(|_| LoadError::SegmentOutOfRange)?;
+    Ok(program)
+}
+
+// 16 bytes for AT_RANDOM, from RDRAND if the CPU has it
+fn random_seed() -> [u8; 16] {
+    let rdrand = x86_64::instructions::random::RdRand::new();
+    let mut seed = [0u8; 16];
+    for chunk in seed.chunks_mut(8) {
+        let value = rdrand.and_then(|rdrand| rdrand.get_u64())
+            .unwrap_or_else(|| unsafe { core::arch::x86_64::_rdtsc() }.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    seed
+}
+
+// Build the System V initial stack: argc at the stack pointer, then
+// the argv and envp pointer arrays and the auxiliary vector, with the
+// strings and random bytes they point to at the top of the stack.
+// Returns the stack pointer
+fn write_initial_stack(process: &Process, args: &[&str], env: &[&str],
+                       auxv: &[(u64, u64)]) -> Result<u64, LoadError> {
+    let stack_end = USER_STACK_START + USER_STACK_SIZE as u64;
+    let strings_size: usize = args.iter().chain(env.iter()).map(|s| s.len() + 1).sum();
+    let words = 1 + (args.len() + 1) + (env.len() + 1) + 2 * (auxv.len() + 2);
+    let random = stack_end.checked_sub(strings_size as u64 + 16).map(|address| address & !0xf);
+    let stack_pointer = random.and_then(|random| random.checked_sub(words as u64 * 8)).map(|address| address & !0xf);
+    let (random, stack_pointer) = match (random, stack_pointer) {
+        (Some(random), Some(stack_pointer)) if stack_pointer >= USER_STACK_START + USER_STACK_RESERVE => (random, stack_pointer),
+        _ => return Err(LoadError::ArgumentsTooLong)
+    };
+
+    let mut strings = Vec::with_capacity(strings_size);
+    let mut pointers = Vec::with_capacity(args.len() + env.len());
+    let strings_start = stack_end - strings_size as u64;
+    for s in args.iter().chain(env.iter()) {
+        pointers.push(strings_start + strings.len() as u64);
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+
+    let mut vector: Vec<u64> = Vec::with_capacity(words);
+    vector.push(args.len() as u64);
+    vector.extend_from_slice(&pointers[..args.len()]);
+    vector.push(0);
+    vector.extend_from_slice(&pointers[args.len()..]);
+    vector.push(0);
+    for &(kind, value) in auxv.iter().chain([(AT_RANDOM, random), (AT_NULL, 0)].iter()) {
+        vector.push(kind);
+        vector.push(value);
+    }
+    let vector: Vec<u8> = vector.iter().flat_map(|word| word.to_le_bytes()).collect();
+
+    let written = write_user(process, strings_start, &strings)
+        && write_user(process, random, &random_seed())
+        && write_user(process, stack_pointer, &vector);
+    if !written {
+        return Err(LoadError::OutOfMemory);
+    }
+    Ok(stack_pointer)
+}
 
-        let source = &bin[segment.p_offset as usize..][..segment.p_filesz as usize];
-        crate::arch::elf::copy_memory(segment_address as usize, source);
+// Load a program into a new address space. `args` and `env` are
+// passed on the stack as argv and envp, with argv[0] the program name
+pub fn new_user_thread(data: ImageData, handles: Vec<Handle>,
+                       args: &[&str], env: &[&str]) -> Result<Box<Thread>, LoadError> {
+    // Dropping the process on failure frees everything mapped so far
+    let mut process = Process::new(handles);
+    let program = load_program(data, &mut process)?;
+    let entry_point = program.base + program.entry_point;
+    let mut auxv = Vec::from([
+        (AT_ENTRY, entry_point),
+        (AT_PAGESZ, 4096),
+        (AT_HANDLES, process.handle_count() as u64),
+    ]);
+    if let Some(phdr) = program.phdr {
+        auxv.extend_from_slice(&[(AT_PHDR, program.base + phdr),
+                                 (AT_PHENT, ELF64_PHDR_SIZE),
+                                 (AT_PHNUM, program.phnum)]);
     }
+    let stack_pointer = write_initial_stack(&process, args, env, &auxv)?;
 
+    let new_thread = user_thread(process.into_shared(), entry_point, stack_pointer);
+    let context = new_thread.context_mut();
+    context.rax = USER_HEAP_START as u64;
+    context.rcx = USER_HEAP_SIZE as u64;
+    Ok(new_thread)
+}
+
+// Start another thread in the address space of `creator`, running
+// from `entry_point` on a stack the caller has set up. `argument` is
+// passed in rdi. The thread shares the creator's handles, pager and
+// exception handler
+pub fn new_thread_in_process(creator: &Thread, entry_point: u64, stack_pointer: u64, argument: u64) -> Option<Box<Thread>> {
+    // User threads start in the code window
+    if entry_point < USER_CODE_START || entry_point >= USER_CODE_END || stack_pointer >= USER_SPACE_END {
+        return None;
+    }
+    let mut new_thread = user_thread(creator.process.clone()?, entry_point, stack_pointer);
+    new_thread.pager = creator.pager.clone();
+    new_thread.exception_handler = creator.exception_handler.clone();
+    new_thread.priority = creator.priority;
+    new_thread.context_mut().rdi = argument;
+    Some(new_thread)
+}
+
+// Start a thread in a copy of `parent`'s address space, in the state
+// `parent` entered the kernel in. The new process gets `handles`
+pub fn fork_thread(parent: &Thread, handles: Vec<Handle>) -> Option<Box<Thread>> {
+    let process = parent.process.as_ref()?.read().fork(handles)?;
+    let context = *parent.context_mut();
+    let mut new_thread = user_thread(process.into_shared(), context.rip, context.rsp);
+    new_thread.pager = parent.pager.clone();
+    new_thread.exception_handler = parent.exception_handler.clone();
+    new_thread.priority = parent.priority;
+    *new_thread.context_mut() = context;
+    Some(new_thread)
+}
+
+fn user_thread(process: Arc<ProcessLock>, entry_point: u64, stack_pointer: u64) -> Box<Thread> {
     let new_thread = {
-        let kernel_stack = Vec::with_capacity(KERNEL_STACK_SIZE);
-        let kernel_stack_end = (VirtAddr::from_ptr(kernel_stack.as_ptr()) + KERNEL_STACK_SIZE).as_u64();
+        let (kernel_stack, kernel_stack_end) = new_kernel_stack();
         let user_stack = Vec::with_capacity(USER_STACK_SIZE);
         let user_stack_end = (VirtAddr::from_ptr(user_stack.as_ptr()) + USER_STACK_SIZE).as_u64();
         let context = kernel_stack_end - INTERRUPT_CONTEXT_SIZE as u64;
+        let page_table_physaddr = process.read().page_table_physaddr();
+        let pcid = process.read().pcid();
+        #[cfg(feature = "kpti")]
+        let user_page_table_physaddr = process.read().user_page_table_physaddr();
 
         Box::new(Thread {
             id: next_id(),
-            handles,
+            process: Some(process),
+            pager: None,
+            exception_handler: None,
+            registers_buffer: 0,
             kernel_stack,
             user_stack,
             kernel_stack_end,
             user_stack_end,
             context,
-            page_table_physaddr: user_page_table_physaddr
+            page_table_physaddr,
+            pcid,
+            #[cfg(feature = "kpti")]
+            user_page_table_physaddr,
+            blocked_since: None,
+            sched: None,
+            timeslice: DEFAULT_TIMESLICE,
+            slice_remaining: DEFAULT_TIMESLICE,
+            privileged: false,
+            priority: DEFAULT_PRIORITY,
+            affinity: None,
+            cpu: None,
+            idle: false
         })
     };
 
-    let context = unsafe {&mut *(new_thread.context as *mut RegisterState)};
+    let context = new_thread.context_mut();
     context.rip = entry_point;
 
     let (code_selector, data_selector) = gdt::get_user_segments();
     context.cs = code_selector.0 as u64;
     context.ss = data_selector.0 as u64;
 
-    if memory::allocate_pages(user_page_table_ptr,
-                           VirtAddr::new(USER_STACK_START),
-                           USER_STACK_SIZE as u64,
-                           PageTableFlags::PRESENT |
-                           PageTableFlags::WRITABLE |
-                           PageTableFlags::USER_ACCESSIBLE).is_err()  {
-        println!("Could not allocate memory");
-    }
-    context.rsp = (USER_STACK_START as u64) + USER_STACK_SIZE as u64;
-    context.rax = USER_HEAP_START as u64;
-    context.rcx = USER_HEAP_SIZE as u64;
-
-    return new_thread;
+    context.rsp = stack_pointer;
+    new_thread
 }
 
 pub fn next_id() -> u64 {
     interrupts::without_interrupts(|| {
         let mut counter = THREAD_COUNTER.write();
         *counter += 1;
-        *counter
+        let id = *counter;
+        THREAD_STATS.write().insert(id, ThreadStats { id, ..Default::default() });
+        id
     })
 }
 
+// Called on every timer interrupt. Returns true if the current thread
+// should be switched out: its timeslice or budget has run out, or a
+// thread of a more urgent scheduling class is ready
+pub fn timer_tick() -> bool {
+    let cpu = percpu::current();
+    // Global time is kept by the first CPU
+    if cpu.index == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    let now = ticks();
+    let mut running_queue = cpu.running_queue.write();
+    let mut current_thread = cpu.current_thread.write();
+    let thread = match current_thread.as_mut() {
+        Some(thread) => thread,
+        None => return true
+    };
+
+    update_stats(thread.id, |stats| stats.ticks += 1);
+    if let Some(sc) = &mut thread.sched {
+        sc.charge();
+    }
+    thread.slice_remaining = thread.slice_remaining.saturating_sub(1);
+    if thread.slice_remaining == 0 {
+        return true;
+    }
+    should_preempt(&mut running_queue, thread, now)
+}
+
+// Called when another CPU has queued a thread here
+pub fn reschedule_requested() -> bool {
+    let cpu = percpu::current();
+    let mut running_queue = cpu.running_queue.write();
+    let mut current_thread = cpu.current_thread.write();
+    match current_thread.as_mut() {
+        Some(thread) => should_preempt(&mut running_queue, thread, ticks()),
+        None => true
+    }
+}
+
+fn should_preempt(queue: &mut VecDeque<Box<Thread>>, current: &mut Thread, now: u64) -> bool {
+    let current_key = match current.sched_key(now) {
+        Some(key) => key,
+        None => return true
+    };
+    queue.iter_mut()
+        .filter_map(|t| t.sched_key(now))
+        .any(|key| key.0 < current_key.0 || (key.0 == 0 && key < current_key))
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// Set the timeslice of a runnable thread. Threads which are blocked
+// in IPC are not reachable from here, so false is returned for them
+pub fn set_timeslice(id: u64, ticks: u64) -> bool {
+    interrupts::without_interrupts(|| {
+        (0..percpu::count()).filter_map(percpu::get).any(|cpu| {
+            let mut running_queue = cpu.running_queue.write();
+            if let Some(thread) = cpu.current_thread.write().as_mut().filter(|t| t.id == id) {
+                thread.set_timeslice(ticks);
+                return true;
+            }
+            running_queue.iter_mut()
+                .find(|t| t.id == id)
+                .map(|thread| thread.set_timeslice(ticks))
+                .is_some()
+        })
+    })
+}
+
+pub fn update_stats(id: u64, f: impl FnOnce(&mut ThreadStats)) {
+    interrupts::without_interrupts(|| {
+        if let Some(stats) = THREAD_STATS.write().get_mut(&id) {
+            f(stats);
+        }
+    });
+}
+
+// The stats of up to `max_records` live threads, by thread id
+pub fn thread_stats(max_records: usize) -> Vec<ThreadStats> {
+    interrupts::without_interrupts(|| {
+        THREAD_STATS.read().values().take(max_records).copied().collect()
+    })
+}
+
+pub fn dump_stats() {
+    let stats: Vec<ThreadStats> = interrupts::without_interrupts(|| {
+        THREAD_STATS.read().values().copied().collect()
+    });
+    println!("Thread stats at tick {}", ticks());
+    println!("   id    ticks switches    sends receives  blocked");
+    for s in stats {
+        println!("{:>5} {:>8} {:>8} {:>8} {:>8} {:>8}",
+                 s.id, s.ticks, s.context_switches, s.ipc_sends, s.ipc_receives, s.blocked_ticks);
+    }
+}
+
+pub fn current_has_exception_handler() -> bool {
+    percpu::current().current_thread.read().as_ref()
+        .map_or(false, |thread| thread.exception_handler.is_some())
+}
+
 pub fn take_current_thread() -> Option<Box<Thread>> {
-    CURRENT_THREAD.write().take()
+    percpu::current().current_thread.write().take()
+}
+
+// Queue on the CPU the thread is pinned to or last ran on. New
+// threads go to the CPU with the fewest queued threads
+fn target_cpu(thread: &Thread) -> &'static PerCpu {
+    thread.affinity.or(thread.cpu)
+        .and_then(percpu::get)
+        .unwrap_or_else(|| {
+            (0..percpu::count()).filter_map(percpu::get)
+                .min_by_key(|cpu| cpu.running_queue.read().len())
+                .unwrap_or_else(percpu::current)
+        })
 }
 
 // Add thread to beginning of queue
-pub fn schedule_thread(thread: Box<Thread>) {
+pub fn schedule_thread(mut thread: Box<Thread>) {
+    thread.unblock();
     // Turn off interrupts while modifying process table
     interrupts::without_interrupts(|| {
-        RUNNING_QUEUE.write().push_front(thread);
+        let cpu = target_cpu(&thread);
+        cpu.running_queue.write().push_front(thread);
+        if cpu.index != percpu::current().index {
+            apic::send_ipi(cpu.apic_id, cpu::InterruptIndex::Reschedule.as_u8());
+        }
     });
 }
 
 // Makes the given thread the current thread
-pub fn set_current_thread(thread: Box<Thread>) {
+pub fn set_current_thread(mut thread: Box<Thread>) {
+    thread.unblock();
     // Replace the current thread
-    let old_current = CURRENT_THREAD.write().replace(thread);
+    let old_current = percpu::current().current_thread.write().replace(thread);
     if let Some(t) = old_current {
         schedule_thread(t);
     }
@@ -219,8 +773,153 @@ impl Thread {
         self.id
     }
 
+    // Start counting blocked time. Called when the thread is
+    // parked in a Rendezvous waiting for a partner
+    pub fn block(&mut self) {
+        self.blocked_since = Some(ticks());
+    }
+
+    fn unblock(&mut self) {
+        if let Some(since) = self.blocked_since.take() {
+            let blocked = ticks() - since;
+            update_stats(self.id, |stats| stats.blocked_ticks += blocked);
+        }
+    }
+
+    // Give the thread a CPU budget, or make it best-effort again with None
+    pub fn set_sched_params(&mut self, params: Option<SchedParams>) -> Result<(), AdmissionError> {
+        self.sched = match params {
+            Some(params) => Some(SchedContext::admit(params, self.sched.as_mut(), ticks())?),
+            None => None
+        };
+        Ok(())
+    }
+
+    // Scheduling class and EDF deadline used to order runnable threads,
+    // or None if the thread has used up its budget for this period
+    fn sched_key(&mut self, now: u64) -> Option<(u8, u64)> {
+        if self.idle {
+            return Some((3, 0));
+        }
+        match &mut self.sched {
+            Some(sc) => {
+                sc.replenish(now);
+                if sc.depleted() {
+                    return None;
+                }
+                match sc.absolute_deadline() {
+                    Some(deadline) => Some((0, deadline)),
+                    None => Some((1, 0))
+                }
+            }
+            None => Some((2, (u8::MAX - self.priority) as u64))
+        }
+    }
+
+    pub fn set_timeslice(&mut self, ticks: u64) {
+        self.timeslice = u64::max(ticks, 1);
+        self.slice_remaining = u64::min(self.slice_remaining, self.timeslice);
+    }
+
+    // Pin the thread to a CPU, or let it run anywhere with None
+    pub fn set_affinity(&mut self, cpu: Option<usize>) {
+        self.affinity = cpu;
+    }
+
+    pub fn is_privileged(&self) -> bool {
+        self.privileged
+    }
+
+    pub fn set_privileged(&mut self, privileged: bool) {
+        self.privileged = privileged;
+    }
+
+    pub fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+    }
+
+    pub fn process(&self) -> Option<Arc<ProcessLock>> {
+        self.process.clone()
+    }
+
     pub fn rendezvous(&self, id: u64) -> Option<Arc<RwLock<Rendezvous>>> {
-        self.handles.get(id as usize).map(|rv| rv.clone())
+        self.process.as_ref()?.read().rendezvous(id)
+    }
+
+    pub fn handle(&self, id: u64) -> Option<Handle> {
+        self.process.as_ref()?.read().handle(id)
+    }
+
+    pub fn pager(&self) -> Option<Arc<RwLock<Rendezvous>>> {
+        self.pager.clone()
+    }
+
+    pub fn set_pager(&mut self, pager: Option<Arc<RwLock<Rendezvous>>>) {
+        self.pager = pager;
+    }
+
+    // Set by ipc_read, see Message::Exception
+    pub fn set_registers_buffer(&mut self, address: u64) {
+        self.registers_buffer = address;
+    }
+
+    pub fn exception_handler(&self) -> Option<Arc<RwLock<Rendezvous>>> {
+        self.exception_handler.clone()
+    }
+
+    pub fn set_exception_handler(&mut self, handler: Option<Arc<RwLock<Rendezvous>>>) {
+        self.exception_handler = handler;
+    }
+
+    pub fn registers(&self) -> RegisterState {
+        self.context_mut().clone()
+    }
+
+    // Replace the saved user mode registers, e.g. on behalf of an
+    // exception handler. The segments and privileged flags are kept
+    pub fn set_user_registers(&mut self, registers: &RegisterState) -> bool {
+        if registers.rip >= USER_SPACE_END || registers.rsp >= USER_SPACE_END {
+            return false;
+        }
+        let context = self.context_mut();
+        let (cs, ss) = (context.cs, context.ss);
+        *context = registers.clone();
+        context.cs = cs;
+        context.ss = ss;
+        context.rflags = (registers.rflags & USER_RFLAGS) | 0x200;
+        true
+    }
+
+    // True if one of the thread's handles refers to `rendezvous`
+    pub fn holds(&self, rendezvous: &Arc<RwLock<Rendezvous>>) -> bool {
+        self.process.as_ref().map_or(false, |process| process.read().holds(rendezvous))
+    }
+
+    pub fn page_table_physaddr(&self) -> u64 {
+        self.page_table_physaddr
+    }
+
+    pub fn mmap(&self, address: u64, length: u64, prot: u64) -> Result<u64, MmapError> {
+        let process = self.process.as_ref().ok_or(MmapError::InvalidArgument)?;
+        process.write().mmap(address, length, prot)
+    }
+
+    pub fn munmap(&self, address: u64, length: u64) -> Result<(), MmapError> {
+        let process = self.process.as_ref().ok_or(MmapError::InvalidArgument)?;
+        process.write().munmap(address, length)
+    }
+
+    pub fn mprotect(&self, address: u64, length: u64, prot: u64) -> Result<(), MmapError> {
+        let process = self.process.as_ref().ok_or(MmapError::InvalidArgument)?;
+        process.write().mprotect(address, length, prot)
+    }
+
+    // Copy a user mode context saved elsewhere, e.g. on an exception
+    // stack, to the top of the thread's kernel stack. That area is
+    // free while the thread is in user mode
+    pub fn save_context(&mut self, context: &RegisterState) {
+        self.context = self.kernel_stack_end - INTERRUPT_CONTEXT_SIZE as u64;
+        *self.context_mut() = context.clone();
     }
 
     fn context_mut(&self) -> &mut RegisterState {
@@ -235,6 +934,13 @@ impl Thread {
         self.context_mut().rax = error_code;
     }
 
+    // Error code in rax and a result in rdi
+    pub fn return_value(&self, error_code: u64, value: u64) {
+        let context = self.context_mut();
+        context.rax = error_code;
+        context.rdi = value;
+    }
+
     pub fn return_message(&self, message: Message) {
         let context = self.context_mut();
         context.rax = 0;
@@ -242,6 +948,42 @@ impl Thread {
             Message::Short(value) => {
                 context.rdi = value;
             }
+            Message::PageFault { thread, address, error_code, rip } => {
+                context.rdi = address;
+                context.rsi = error_code;
+                context.rdx = rip;
+                context.r8 = thread;
+            }
+            Message::Exception { thread, vector, error_code, address, registers } => {
+                context.rdi = thread;
+                context.rsi = vector;
+                context.rdx = error_code;
+                context.r8 = address;
+                // This may not be the current address space, so write
+                // through the receiving process
+                if self.registers_buffer != 0 {
+                    let bytes = unsafe {
+                        core::slice::from_raw_parts(&registers as *const RegisterState as *const u8,
+                                                    core::mem::size_of::<RegisterState>())
+                    };
+                    let written = self.process.as_ref()
+                        .map_or(false, |process| write_user(&process.read(), self.registers_buffer, bytes));
+                    if !written {
+                        context.rax = SYSCALL_ERROR_INVALID_ARGUMENT;
+                    }
+                }
+            }
         }
     }
+}
+
+// The process is freed with its last thread
+impl Drop for Thread {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            THREAD_STATS.write().remove(&self.id);
+        });
+        #[cfg(feature = "kpti")]
+        memory::unmap_entry_range(VirtAddr::from_ptr(self.kernel_stack.as_ptr()), KERNEL_STACK_SIZE as u64);
+    }
 }
\ No newline at end of file
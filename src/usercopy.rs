@@ -0,0 +1,190 @@
+extern crate alloc;
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+use core::arch::x86_64::__cpuid_count;
+use core::{mem, slice};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::arch::arch::get_cr3;
+use crate::memory;
+use crate::process;
+use crate::threads::USER_SPACE_END;
+
+// CPUID leaf 7 EBX feature bits
+const CPUID_SMEP: u32 = 1 << 7;
+const CPUID_SMAP: u32 = 1 << 20;
+
+// stac and clac fault if the CPU doesn't have SMAP
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+    BadAddress,  // Not entirely mapped in user space, or misaligned
+    OutOfMemory, // No room in the kernel heap for the copy
+}
+
+// Copy rdx bytes from rsi to rdi. Returns 0, or 1 if the copy
+// faulted. A page fault at user_copy_fault resumes at user_copy_fixup
+// through the exception fixup table
+global_asm!(
+    ".global user_copy_bytes",
+    "user_copy_bytes:",
+    "mov rcx, rdx",
+    "user_copy_fault:",
+    "rep movsb",
+    "xor eax, eax",
+    "ret",
+    "user_copy_fixup:",
+    "mov eax, 1",
+    "ret",
+
+    // Pairs of faulting instruction and where to continue instead
+    ".pushsection .data.rel.ro",
+    ".balign 8",
+    ".global exception_fixup_table",
+    ".global exception_fixup_table_end",
+    "exception_fixup_table:",
+    ".quad user_copy_fault, user_copy_fixup",
+    "exception_fixup_table_end:",
+    ".popsection",
+);
+
+#[repr(C)]
+struct Fixup {
+    fault: u64,
+    resume: u64,
+}
+
+extern "C" {
+    fn user_copy_bytes(destination: *mut u8, source: *const u8, length: usize) -> u64;
+    static exception_fixup_table: Fixup;
+    static exception_fixup_table_end: Fixup;
+}
+
+// Where to continue after a fault in the kernel at `rip`, if the
+// instruction is allowed to fault
+pub fn fixup(rip: u64) -> Option<u64> {
+    let table = unsafe {
+        let start = &exception_fixup_table as *const Fixup;
+        let end = &exception_fixup_table_end as *const Fixup;
+        slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    table.iter().find(|entry| entry.fault == rip).map(|entry| entry.resume)
+}
+
+// Stop the kernel executing user pages (SMEP) and touching them
+// outside the copy routines below (SMAP), if the CPU supports it.
+// Other CPUs copy CR4 from this one when they start
+pub fn init() {
+    let max_leaf = __cpuid_count(0, 0).eax;
+    let leaf7 = if max_leaf >= 7 { __cpuid_count(7, 0).ebx } else { 0 };
+    let mut flags = Cr4::read();
+    if leaf7 & CPUID_SMEP != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if leaf7 & CPUID_SMAP != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+        SMAP_ENABLED.store(true, Ordering::SeqCst);
+    }
+    unsafe { Cr4::write(flags) };
+}
+
+// Check that `count` values of T at `address` are in user space.
+// Returns their size in bytes
+fn check_range<T>(address: u64, count: u64) -> Result<u64, UserCopyError> {
+    let length = count.checked_mul(mem::size_of::<T>() as u64).ok_or(UserCopyError::BadAddress)?;
+    if length == 0 {
+        return Ok(0);
+    }
+    let end = address.checked_add(length).ok_or(UserCopyError::BadAddress)?;
+    if address == 0 || end > USER_SPACE_END || address % mem::align_of::<T>() as u64 != 0 {
+        return Err(UserCopyError::BadAddress);
+    }
+    Ok(length)
+}
+
+// Check that the user page containing `address` is mapped in the
+// current page table, writable if `write` is set. A page not yet
+// touched is filled in first, as a fault from user mode would
+fn check_page(address: u64, write: bool) -> Result<(), UserCopyError> {
+    let mut required = PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let page = address & !0xfff;
+    let accessible = matches!(memory::translate(get_cr3() & !0xfff, VirtAddr::new(page)),
+                              Some((_, flags)) if flags.contains(required));
+    if !accessible && !process::handle_fault(page, write) {
+        return Err(UserCopyError::BadAddress);
+    }
+    Ok(())
+}
+
+// Copy `length` bytes between `kernel` and a checked user range, a
+// page at a time. Each user page is checked just before it is copied,
+// so a bad range fails without filling in all the pages before it
+unsafe fn copy_pages(kernel: *mut u8, user: u64, length: u64, to_user: bool) -> Result<(), UserCopyError> {
+    let mut done = 0;
+    while done < length {
+        let address = user + done;
+        let chunk = u64::min(length - done, 4096 - address % 4096);
+        check_page(address, to_user)?;
+        let kernel = kernel.add(done as usize);
+        if to_user {
+            copy_bytes(address as *mut u8, kernel, chunk as usize)?;
+        } else {
+            copy_bytes(kernel, address as *const u8, chunk as usize)?;
+        }
+        done += chunk;
+    }
+    Ok(())
+}
+
+// Copy with user access allowed. The pages have been checked, but
+// another thread may unmap them before the copy is done
+unsafe fn copy_bytes(destination: *mut u8, source: *const u8, length: usize) -> Result<(), UserCopyError> {
+    let smap = SMAP_ENABLED.load(Ordering::Relaxed);
+    if smap {
+        asm!("stac", options(nomem, nostack));
+    }
+    let faulted = user_copy_bytes(destination, source, length);
+    if smap {
+        asm!("clac", options(nomem, nostack));
+    }
+    if faulted != 0 {
+        return Err(UserCopyError::BadAddress);
+    }
+    Ok(())
+}
+
+pub fn copy_from_user<T: Copy>(destination: &mut [T], source: *const T) -> Result<(), UserCopyError> {
+    let length = check_range::<T>(source as u64, destination.len() as u64)?;
+    unsafe { copy_pages(destination.as_mut_ptr() as *mut u8, source as u64, length, false) }
+}
+
+pub fn copy_to_user<T: Copy>(destination: *mut T, source: &[T]) -> Result<(), UserCopyError> {
+    let length = check_range::<T>(destination as u64, source.len() as u64)?;
+    unsafe { copy_pages(source.as_ptr() as *mut u8, destination as u64, length, true) }
+}
+
+// Copy `count` values from user memory into a new vector. The vector
+// is allocated before any user page is filled in
+pub fn read_vec<T: Copy + Default>(source: *const T, count: u64) -> Result<Vec<T>, UserCopyError> {
+    check_range::<T>(source as u64, count)?;
+    let mut values = Vec::new();
+    values.try_reserve_exact(count as usize).map_err(|_| UserCopyError::OutOfMemory)?;
+    values.resize(count as usize, T::default());
+    copy_from_user(&mut values, source)?;
+    Ok(values)
+}
+
+pub fn read<T: Copy>(source: *const T) -> Result<T, UserCopyError> {
+    let length = check_range::<T>(source as u64, 1)?;
+    let mut value = mem::MaybeUninit::<T>::uninit();
+    unsafe {
+        copy_pages(value.as_mut_ptr() as *mut u8, source as u64, length, false)?;
+        Ok(value.assume_init())
+    }
+}
\ No newline at end of file
@@ -86,7 +86,9 @@ impl fmt::Write for Writer {
 lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        // Through the physical memory mapping, which every address
+        // space shares. memory::init runs before the first print
+        buffer: unsafe { &mut *crate::memory::physical_to_virtual(0xb8000).as_mut_ptr::<Buffer>() },
     });
 }
 